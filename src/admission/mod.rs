@@ -0,0 +1,135 @@
+// Caps on the connections open at the same time and on the websockets of a
+// client address. A connection holds a permit from accept until it is closed,
+// one in the handshake is counted as much as an open websocket. A websocket
+// holds a permit of its client address from its handshake on. Counts go down
+// when the permits are dropped.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use tokio::time::Duration;
+use crate::error::http_errors::{ErrorKind, HTTPError};
+use crate::service_config::{AdmissionConfig, LimitAction};
+
+static OPEN_CONNECTIONS: AtomicUsize = AtomicUsize::new(0);
+
+lazy_static! {
+    static ref OPEN_CONNECTIONS_BY_IP: Mutex<HashMap<IpAddr, usize>> = Mutex::new(HashMap::new());
+}
+
+pub struct ConnectionPermit {
+    _private: ()
+}
+
+impl Drop for ConnectionPermit {
+    fn drop(&mut self) {
+        OPEN_CONNECTIONS.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+pub struct AdmissionPermit {
+    client_ip: IpAddr
+}
+
+impl Drop for AdmissionPermit {
+    fn drop(&mut self) {
+        release_ip(self.client_ip);
+    }
+}
+
+pub fn open_connections() -> usize {
+    OPEN_CONNECTIONS.load(Ordering::SeqCst)
+}
+
+// taken on accept, before anything is read. None over the cap, the connection
+// is closed then or kept only to answer its request with a 503.
+pub fn admit_connection(admission_config: &AdmissionConfig) -> Option<ConnectionPermit> {
+    let max_connections = admission_config.max_connections;
+    let admitted = OPEN_CONNECTIONS.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |open_connections| {
+        if max_connections > 0 && open_connections >= max_connections {
+            None
+        } else {
+            Some(open_connections + 1)
+        }
+    });
+    if admitted.is_err() {
+        crate::error!("Connection cap of {} reached",max_connections);
+        return None;
+    }
+    Some(ConnectionPermit { _private: () })
+}
+
+pub fn closes_when_limited(admission_config: &AdmissionConfig) -> bool {
+    admission_config.when_limited == LimitAction::Close
+}
+
+// a websocket is only opened on a connection which got a permit on accept
+pub fn admit(client_ip: IpAddr, connection_admitted: bool, admission_config: &AdmissionConfig) -> Result<AdmissionPermit, HTTPError> {
+    if !connection_admitted {
+        return Err(limited(admission_config, "too many connections"));
+    }
+    if !acquire_ip(client_ip, admission_config.max_connections_per_ip) {
+        crate::error!("Connection cap of {} reached for {}",admission_config.max_connections_per_ip,client_ip);
+        return Err(limited(admission_config, "too many connections from this address"));
+    }
+    Ok(AdmissionPermit { client_ip })
+}
+
+fn limited(admission_config: &AdmissionConfig, message: &str) -> HTTPError {
+    match admission_config.when_limited {
+        LimitAction::ServiceUnavailable => HTTPError::new(ErrorKind::ServiceUnavailable, message),
+        LimitAction::Close => HTTPError::new(ErrorKind::ConnectionRefused, message)
+    }
+}
+
+// connections are counted by address even without a per address cap, the
+// count is dropped with the last connection of the address
+fn acquire_ip(client_ip: IpAddr, max_connections_per_ip: usize) -> bool {
+    let mut open_connections_by_ip = OPEN_CONNECTIONS_BY_IP.lock().unwrap();
+    let open_connections = open_connections_by_ip.entry(client_ip).or_insert(0);
+    if max_connections_per_ip > 0 && *open_connections >= max_connections_per_ip {
+        return false;
+    }
+    *open_connections += 1;
+    true
+}
+
+fn release_ip(client_ip: IpAddr) {
+    let mut open_connections_by_ip = OPEN_CONNECTIONS_BY_IP.lock().unwrap();
+    if let Some(open_connections) = open_connections_by_ip.get_mut(&client_ip) {
+        *open_connections -= 1;
+        if *open_connections == 0 {
+            open_connections_by_ip.remove(&client_ip);
+        }
+    }
+}
+
+// pause after a failed accept. Out of file descriptors every accept fails
+// right away, retrying without a pause would only spin.
+pub struct AcceptBackoff {
+    initial: Duration,
+    max: Duration,
+    next: Duration
+}
+
+impl AcceptBackoff {
+    pub fn new(admission_config: &AdmissionConfig) -> AcceptBackoff {
+        let initial = Duration::from_millis(admission_config.accept_backoff_initial_ms);
+        AcceptBackoff {
+            initial,
+            max: Duration::from_millis(admission_config.accept_backoff_max_ms),
+            next: initial
+        }
+    }
+
+    pub async fn wait(&mut self) {
+        crate::info!("Accepting again in {:?}",self.next);
+        tokio::time::sleep(self.next).await;
+        self.next = (self.next * 2).min(self.max);
+    }
+
+    pub fn reset(&mut self) {
+        self.next = self.initial;
+    }
+}
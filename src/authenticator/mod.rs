@@ -0,0 +1,278 @@
+// Identifies the user opening a connection. Runs during the handshake, a
+// request which can not be authenticated gets a 401, one carrying a valid token
+// not meant for this server gets a 403.
+
+use std::fs;
+use http::Request;
+use http::header::{AUTHORIZATION, COOKIE, HeaderName};
+use jsonwebtoken::{Algorithm, DecodingKey, Validation};
+use jsonwebtoken::errors::ErrorKind as JwtErrorKind;
+use crate::error::http_errors::{ErrorKind, HTTPError, Result};
+use crate::route_table::RouteMatch;
+use crate::service_config::{AuthenticationConfig, JwtConfig};
+
+lazy_static! {
+    pub static ref AUTHENTICATOR: Box<dyn Authenticator> = {
+        new_authenticator(&crate::SERVICE_CONFIG.authentication)
+    };
+}
+
+// who the connection belongs to
+#[derive(Debug)]
+pub struct Identity {
+    pub user_id: String
+}
+
+pub trait Authenticator: Send + Sync {
+    fn authenticate(&self, request: &Request<()>, route_match: &RouteMatch) -> Result<Identity>;
+}
+
+pub fn new_authenticator(authentication_config: &AuthenticationConfig) -> Box<dyn Authenticator> {
+    match authentication_config {
+        AuthenticationConfig::UserIdHeader => Box::new(UserIdHeaderAuthenticator {}),
+        AuthenticationConfig::Jwt(jwt_config) => Box::new(JwtAuthenticator::new(jwt_config))
+    }
+}
+
+// trusts the user-id header sent by the client
+pub struct UserIdHeaderAuthenticator {}
+
+impl Authenticator for UserIdHeaderAuthenticator {
+    fn authenticate(&self, request: &Request<()>, _route_match: &RouteMatch) -> Result<Identity> {
+        let user_id_header: HeaderName = HeaderName::from_static("user-id");
+        crate::info!("Checking User Id Header: {}",user_id_header);
+        match request.headers().get(user_id_header).map(|header_value| header_value.to_str()) {
+            Some(Ok(user_id)) => Ok(Identity { user_id: user_id.to_string() }),
+            _ => {
+                crate::error!("No User-id Header found");
+                Err(HTTPError::new(ErrorKind::Unauthorized, "user-id header not found"))
+            }
+        }
+    }
+}
+
+// verifies a HS256 or RS256 signed JWT, user id is taken from one of its claims
+pub struct JwtAuthenticator {
+    decoding_key: DecodingKey,
+    validation: Validation,
+    user_id_claim: String,
+    token_query_param: String,
+    token_cookie: Option<String>
+}
+
+impl JwtAuthenticator {
+    // key file is read once, a server which can not read it should not start
+    pub fn new(jwt_config: &JwtConfig) -> JwtAuthenticator {
+        let key_bytes = fs::read(&jwt_config.key_file)
+            .expect("Unable to read JWT key file");
+        let decoding_key = match jwt_config.algorithm {
+            // secret files usually end with a new line which is not part of the secret
+            Algorithm::HS256 => DecodingKey::from_secret(key_bytes.trim_ascii_end()),
+            Algorithm::RS256 => DecodingKey::from_rsa_pem(&key_bytes)
+                .expect("JWT key file is not a PEM encoded RSA public key"),
+            _ => panic!("Only HS256 and RS256 JWT are supported")
+        };
+
+        let mut validation = Validation::new(jwt_config.algorithm);
+        validation.leeway = jwt_config.leeway_secs;
+        if !jwt_config.audience.is_empty() {
+            validation.set_audience(&jwt_config.audience);
+        }
+        if let Some(issuer) = &jwt_config.issuer {
+            validation.set_issuer(&[issuer]);
+        }
+        JwtAuthenticator {
+            decoding_key,
+            validation,
+            user_id_claim: jwt_config.user_id_claim.clone(),
+            token_query_param: jwt_config.token_query_param.clone(),
+            token_cookie: jwt_config.token_cookie.clone()
+        }
+    }
+
+    //  Authorization: Bearer <token>
+    //
+    // browsers can not set headers on a websocket request, they send the token
+    // as a query parameter or in a cookie instead
+    fn find_token<'a>(&self, request: &'a Request<()>, route_match: &'a RouteMatch) -> Option<&'a str> {
+        for header_value in request.headers().get_all(AUTHORIZATION) {
+            if let Some(token) = header_value.to_str().ok().and_then(|value| value.strip_prefix("Bearer ")) {
+                return Some(token.trim());
+            }
+        }
+        if let Some(token) = route_match.query_params.get(&self.token_query_param) {
+            return Some(token);
+        }
+        let cookie_name = self.token_cookie.as_ref()?;
+        for header_value in request.headers().get_all(COOKIE) {
+            let cookies = match header_value.to_str() {
+                Ok(cookies) => cookies,
+                Err(_) => continue
+            };
+            for cookie in cookies.split(';') {
+                match cookie.trim().split_once('=') {
+                    Some((name, value)) if name == cookie_name => return Some(value),
+                    _ => {}
+                }
+            }
+        }
+        None
+    }
+}
+
+impl Authenticator for JwtAuthenticator {
+    fn authenticate(&self, request: &Request<()>, route_match: &RouteMatch) -> Result<Identity> {
+        let token = match self.find_token(request, route_match) {
+            Some(token) => token,
+            None => {
+                crate::error!("No token found in handshake request");
+                return Err(HTTPError::new(ErrorKind::Unauthorized, "token not found"));
+            }
+        };
+        let token_data = match jsonwebtoken::decode::<serde_json::Value>(token, &self.decoding_key, &self.validation) {
+            Ok(token_data) => token_data,
+            Err(e) => {
+                crate::error!("Token rejected: {}",e);
+                // token is genuine but was not issued for this server
+                return match e.kind() {
+                    JwtErrorKind::InvalidAudience | JwtErrorKind::InvalidIssuer => {
+                        Err(HTTPError::new(ErrorKind::Forbidden, "token not issued for this server"))
+                    }
+                    _ => Err(HTTPError::new(ErrorKind::Unauthorized, "invalid token"))
+                };
+            }
+        };
+        match token_data.claims.get(&self.user_id_claim).and_then(|claim| claim.as_str()) {
+            Some(user_id) => Ok(Identity { user_id: user_id.to_string() }),
+            None => {
+                crate::error!("Token does not have the claim: {}",self.user_id_claim);
+                Err(HTTPError::new(ErrorKind::Forbidden, "token does not name a user"))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::time::{SystemTime, UNIX_EPOCH};
+    use http::StatusCode;
+    use jsonwebtoken::{EncodingKey, Header};
+    use serde_json::json;
+    use crate::service_config::{RouteConfig, SizeLimits};
+    use super::*;
+
+    const SECRET: &[u8] = b"pollux-test-secret";
+
+    fn jwt_authenticator(name: &str) -> JwtAuthenticator {
+        // the secret file ends with a new line like the ones written by hand
+        let key_file = std::env::temp_dir().join(format!("pollux-{}-{}.key", name, std::process::id()));
+        fs::write(&key_file, [SECRET, b"\n"].concat()).unwrap();
+        let authenticator = JwtAuthenticator::new(&JwtConfig {
+            algorithm: Algorithm::HS256,
+            key_file: key_file.to_str().unwrap().to_string(),
+            audience: vec!["pollux".to_string()],
+            issuer: Some("https://auth.example.com".to_string()),
+            user_id_claim: "sub".to_string(),
+            token_query_param: "token".to_string(),
+            token_cookie: Some("session".to_string()),
+            leeway_secs: 0
+        });
+        let _ = fs::remove_file(key_file);
+        authenticator
+    }
+
+    fn token(secret: &[u8], claims: serde_json::Value) -> String {
+        jsonwebtoken::encode(&Header::default(), &claims, &EncodingKey::from_secret(secret)).unwrap()
+    }
+
+    fn claims(audience: &str, issuer: &str, expires_in: i64) -> serde_json::Value {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as i64;
+        json!({ "sub": "alice", "aud": audience, "iss": issuer, "exp": now + expires_in })
+    }
+
+    fn valid_claims() -> serde_json::Value {
+        claims("pollux", "https://auth.example.com", 300)
+    }
+
+    fn route_match(query_params: &[(&str, &str)]) -> RouteMatch {
+        RouteMatch {
+            route: RouteConfig {
+                path: "/chat".to_string(),
+                handler: Default::default(),
+                sub_protocols: Vec::new(),
+                size_limits: None,
+                streaming: None
+            },
+            path_params: HashMap::new(),
+            query_params: query_params.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect(),
+            default_size_limits: SizeLimits::default()
+        }
+    }
+
+    fn bearer_request(token: &str) -> Request<()> {
+        Request::builder().header(AUTHORIZATION, format!("Bearer {}", token)).body(()).unwrap()
+    }
+
+    fn status_code(result: Result<Identity>) -> StatusCode {
+        match result {
+            Ok(identity) => panic!("{} authenticated", identity.user_id),
+            Err(e) => e.status_code()
+        }
+    }
+
+    #[test]
+    fn hs256_token_is_accepted() {
+        let authenticator = jwt_authenticator("accept");
+        let identity = authenticator.authenticate(&bearer_request(&token(SECRET, valid_claims())), &route_match(&[])).unwrap();
+        assert_eq!(identity.user_id, "alice");
+    }
+
+    #[test]
+    fn token_signed_with_another_secret_is_rejected() {
+        let authenticator = jwt_authenticator("reject");
+        let request = bearer_request(&token(b"another-secret", valid_claims()));
+        assert_eq!(status_code(authenticator.authenticate(&request, &route_match(&[]))), StatusCode::UNAUTHORIZED);
+    }
+
+    #[test]
+    fn expired_token_is_rejected() {
+        let authenticator = jwt_authenticator("expired");
+        let request = bearer_request(&token(SECRET, claims("pollux", "https://auth.example.com", -300)));
+        assert_eq!(status_code(authenticator.authenticate(&request, &route_match(&[]))), StatusCode::UNAUTHORIZED);
+    }
+
+    #[test]
+    fn token_for_another_audience_or_issuer_is_forbidden() {
+        let authenticator = jwt_authenticator("forbidden");
+        let request = bearer_request(&token(SECRET, claims("another-service", "https://auth.example.com", 300)));
+        assert_eq!(status_code(authenticator.authenticate(&request, &route_match(&[]))), StatusCode::FORBIDDEN);
+        let request = bearer_request(&token(SECRET, claims("pollux", "https://another-issuer.example.com", 300)));
+        assert_eq!(status_code(authenticator.authenticate(&request, &route_match(&[]))), StatusCode::FORBIDDEN);
+    }
+
+    #[test]
+    fn token_is_found_in_query_and_cookie() {
+        let authenticator = jwt_authenticator("found");
+        let token = token(SECRET, valid_claims());
+        let request = Request::builder().body(()).unwrap();
+        assert_eq!(authenticator.authenticate(&request, &route_match(&[("token", &token)])).unwrap().user_id, "alice");
+        let request = Request::builder().header(COOKIE, format!("theme=dark; session={}", token)).body(()).unwrap();
+        assert_eq!(authenticator.authenticate(&request, &route_match(&[])).unwrap().user_id, "alice");
+    }
+
+    #[test]
+    fn missing_token_is_unauthorized() {
+        let authenticator = jwt_authenticator("missing");
+        let token = token(SECRET, valid_claims());
+        // no header, no query parameter, no cookie
+        let request = Request::builder().body(()).unwrap();
+        assert_eq!(status_code(authenticator.authenticate(&request, &route_match(&[]))), StatusCode::UNAUTHORIZED);
+        // not a bearer token
+        let request = Request::builder().header(AUTHORIZATION, format!("Basic {}", token)).body(()).unwrap();
+        assert_eq!(status_code(authenticator.authenticate(&request, &route_match(&[]))), StatusCode::UNAUTHORIZED);
+        // token under other query parameter and cookie names
+        let request = Request::builder().header(COOKIE, format!("token={}", token)).body(()).unwrap();
+        assert_eq!(status_code(authenticator.authenticate(&request, &route_match(&[("access_token", &token)]))), StatusCode::UNAUTHORIZED);
+    }
+}
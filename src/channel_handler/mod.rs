@@ -1,6 +1,13 @@
-use tokio::sync::mpsc::Receiver;
+use tokio::sync::mpsc::Sender;
+use crate::data_frame::{DataFrameInfo, ReadFrom};
 
-pub async fn read_vector_from_channel(rx: &mut Receiver<Vec<u8>>) -> Result<Vec<u8>, &'static str> {
-    let bytes_data = rx.recv().await.unwrap();
-    Ok(bytes_data)
+// frames are handed over to the task serving the user, which writes them to its socket
+pub async fn send_dataframe_to_channel(mut data_frame: DataFrameInfo, tx: &Sender<DataFrameInfo>) {
+    crate::info!("Sending message to channel");
+    data_frame.read_from = ReadFrom::Channel;
+    if tx.send(data_frame).await.is_err() {
+        crate::error!("Receiver of the channel is closed");
+        return;
+    }
+    crate::info!("Message Sent");
 }
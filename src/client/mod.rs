@@ -0,0 +1,176 @@
+// Client mode, pollux connecting to a websocket server. Frames sent on the
+// connection are masked, frames received must not be.
+
+use bytes::BytesMut;
+use http::Response;
+use http::header::HeaderName;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio_util::codec::{FramedRead, FramedWrite};
+use crate::data_frame::{FrameValidationRules, Masking, ReadFrom};
+use crate::data_frame::codec::{FrameDecoder, FrameEncoder};
+use crate::error::client_errors::{self, ClientError, ErrorKind};
+use crate::http_handler::client_handshake;
+use crate::tcp_handler::{self, PrefixedReader};
+
+// a handshake response bigger than this is not coming from a websocket server
+static MAX_HANDSHAKE_RESPONSE_SIZE: usize = 8192;
+
+pub struct ClientConnection {
+    pub frame_reader: FramedRead<PrefixedReader<OwnedReadHalf>, FrameDecoder>,
+    pub frame_writer: FramedWrite<OwnedWriteHalf, FrameEncoder>,
+    // subprotocol selected by the server, if any was offered
+    pub sub_protocol: Option<String>
+}
+
+pub async fn connect(address: &str, path: &str, sub_protocols: &[&str], extra_headers: &[(HeaderName, String)]) -> client_errors::Result<ClientConnection> {
+    crate::info!("Connecting to websocket server: {}{}",address,path);
+    let client_handshake = client_handshake::create_client_handshake_request(address, path, sub_protocols, extra_headers)?;
+    // connecting and the whole handshake have to be done in the handshake timeout
+    let deadline = tcp_handler::handshake_deadline(&crate::SERVICE_CONFIG.handshake);
+    let socket = match tokio::time::timeout_at(deadline, TcpStream::connect(address)).await {
+        Ok(Ok(socket)) => socket,
+        Ok(Err(e)) => {
+            crate::error!("Not able to connect to {}: {}",address,e);
+            return Err(ClientError::new(ErrorKind::ConnectionFailed, "not able to connect to server"));
+        }
+        Err(_) => return Err(handshake_timed_out(address))
+    };
+    let (mut read_half, mut write_half) = socket.into_split();
+
+    let request_bytes = client_handshake::get_http_request_bytes(&client_handshake.request);
+    let handshake = async {
+        if let Err(e) = write_half.write_all(&request_bytes).await {
+            crate::error!("Not able to send handshake request: {}",e);
+            return Err(ClientError::new(ErrorKind::ConnectionFailed, "not able to send handshake request"));
+        }
+        read_handshake_response(&mut read_half).await
+    };
+    let (response, head_length, mut response_bytes) = match tokio::time::timeout_at(deadline, handshake).await {
+        Ok(result) => result?,
+        Err(_) => return Err(handshake_timed_out(address))
+    };
+    let sub_protocol = client_handshake::validate_handshake_response(&response, &client_handshake)?;
+    crate::info!("Connected to websocket server: {}{}, subprotocol: {:?}",address,path,sub_protocol);
+
+    //  A client MUST close a connection if it detects a masked frame.
+    let validation_rules = FrameValidationRules {
+        masking: Masking::Forbidden,
+        rsv1_allowed: false,
+        max_frame_size: crate::SERVICE_CONFIG.size_limits.max_frame_size
+    };
+    // server can send frames right after its response, they arrived with the response
+    let _ = response_bytes.split_to(head_length);
+    let read_half = PrefixedReader::new(response_bytes, read_half);
+    let frame_reader = FramedRead::new(read_half, FrameDecoder::new(validation_rules, ReadFrom::Socket));
+
+    Ok(ClientConnection {
+        frame_reader,
+        frame_writer: FramedWrite::new(write_half, FrameEncoder::new_client()),
+        sub_protocol
+    })
+}
+
+fn handshake_timed_out(address: &str) -> ClientError {
+    crate::error!("Handshake with {} not done in {}s",address,crate::SERVICE_CONFIG.handshake.timeout_secs);
+    ClientError::new(ErrorKind::Timeout, "handshake not done in time")
+}
+
+// returns the response, the length of its head and every byte read, frames
+// the server sent right after its response included
+async fn read_handshake_response(read_half: &mut OwnedReadHalf) -> client_errors::Result<(Response<()>, usize, BytesMut)> {
+    let mut response_bytes = BytesMut::with_capacity(1024);
+    loop {
+        match read_half.read_buf(&mut response_bytes).await {
+            Ok(0) => return Err(ClientError::new(ErrorKind::ConnectionFailed, "connection closed during handshake")),
+            Ok(_) => {}
+            Err(e) => {
+                crate::error!("Not able to read handshake response: {}",e);
+                return Err(ClientError::new(ErrorKind::ConnectionFailed, "not able to read handshake response"));
+            }
+        }
+        if let Some((response, head_length)) = client_handshake::parse_http_response_bytes(&response_bytes)? {
+            return Ok((response, head_length, response_bytes));
+        }
+        if response_bytes.len() > MAX_HANDSHAKE_RESPONSE_SIZE {
+            return Err(ClientError::new(ErrorKind::InvalidResponse, "handshake response too big"));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::{SinkExt, StreamExt};
+    use http::header::ORIGIN;
+    use tokio::net::TcpListener;
+    use crate::data_frame::{Opcode, new_dataframe};
+    use crate::service_config::ListenerConfig;
+    use super::*;
+
+    fn test_listener() -> ListenerConfig {
+        ListenerConfig {
+            name: "client-test".to_string(),
+            address: "127.0.0.1:0".to_string(),
+            dual_stack: false,
+            tls: false,
+            routes: None,
+            host_names: vec!["*".to_string()],
+            size_limits: None
+        }
+    }
+
+    fn user_id_header(user_id: &str) -> (HeaderName, String) {
+        (HeaderName::from_static("user-id"), user_id.to_string())
+    }
+
+    // reads the request and answers it with head
+    async fn answer_handshake(listener: TcpListener, head: &'static str) {
+        let (socket, _) = listener.accept().await.unwrap();
+        let (mut read_half, mut write_half) = socket.into_split();
+        let handshake_config = &crate::SERVICE_CONFIG.handshake;
+        crate::tcp_handler::read_http_request(&mut read_half, handshake_config, crate::tcp_handler::handshake_deadline(handshake_config)).await.unwrap();
+        write_half.write_all(head.as_bytes()).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn exchanges_a_frame_with_the_server() {
+        let tcp_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = tcp_listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            let (socket, socket_address) = tcp_listener.accept().await.unwrap();
+            let (read_half, write_half) = socket.into_split();
+            let handshake_deadline = crate::tcp_handler::handshake_deadline(&crate::SERVICE_CONFIG.handshake);
+            crate::serve_http1_connection(read_half, write_half, socket_address.ip(), &test_listener(), handshake_deadline, true).await;
+        });
+
+        // the Sec-WebSocket-Accept of the response is checked by connect
+        // with the default origin policy a client has to send an Origin
+        let headers = [user_id_header("client-test-chat"), (ORIGIN, "http://localhost".to_string())];
+        let mut connection = connect(&address, "/chat", &[], &headers).await.unwrap();
+        assert_eq!(connection.sub_protocol, None);
+        // a chat message to the user of the connection comes back on it
+        let message = br#"{"sender_user_id":"client-test-chat","message":"hello"}"#;
+        connection.frame_writer.send(new_dataframe(Opcode::TextFrame, message.to_vec())).await.unwrap();
+        let reply = connection.frame_reader.next().await.unwrap().unwrap();
+        assert!(!reply.contain_masked_data);
+        assert_eq!(reply.opcode, Opcode::TextFrame);
+        assert_eq!(reply.payload_data, message);
+    }
+
+    #[tokio::test]
+    async fn wrong_accept_key_is_refused() {
+        let tcp_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = tcp_listener.local_addr().unwrap().to_string();
+        // the accept key of the example in RFC 6455, not the one of this request
+        tokio::spawn(answer_handshake(tcp_listener, "HTTP/1.1 101 Switching Protocols\r\n\
+            Upgrade: websocket\r\n\
+            Connection: Upgrade\r\n\
+            Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n\r\n"));
+
+        match connect(&address, "/chat", &[], &[user_id_header("client-test-accept")]).await {
+            Err(e) => assert_eq!(e.error_kind(), ErrorKind::HandshakeRejected),
+            Ok(_) => panic!("handshake with a wrong Sec-WebSocket-Accept accepted")
+        }
+    }
+}
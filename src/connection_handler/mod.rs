@@ -0,0 +1,384 @@
+// Serves a websocket connection once the handshake is done. Works over any
+// AsyncRead/AsyncWrite pair, frames are parsed and written by the frame codec.
+
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::Poll;
+use bytes::BytesMut;
+use futures_util::{SinkExt, StreamExt};
+use futures_util::future::poll_fn;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::mpsc;
+use tokio::sync::mpsc::Receiver;
+use tokio::time::{Duration, Instant, Sleep};
+use tokio_util::codec::{FramedRead, FramedWrite};
+use crate::{channel_handler, data_frame, utf8_validator, workers};
+use crate::{REDIS_CLIENT, SERVICE_CONFIG, TCP_WORKER_ADDRESS, USER_ID_MAPPING};
+use crate::data_frame::{CloseCode, DataFrameInfo, FrameValidationRules, Masking, Opcode, ReadFrom};
+use crate::data_frame::codec::{FrameDecoder, FrameEncoder};
+use crate::data_frame::payload_reader::{self, PayloadReader};
+use crate::error::data_frame_error::{DataFrameError, ErrorKind, Result};
+use crate::fragment_handler::FragmentHandler;
+use crate::heartbeat::{Heartbeat, HeartbeatAction};
+use crate::http_handler::HandshakeDetails;
+use crate::model::Message;
+use crate::permessage_deflate::DeflateContext;
+use crate::service_config::{AuthenticationConfig, HandlerKind};
+use crate::sub_protocol::{self, MessageCodec};
+use crate::tcp_handler::PrefixedReader;
+
+// time given to the client to reply to a Close frame sent by the server
+static CLOSE_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+// a streamed payload is copied in chunks of this size
+const STREAM_CHUNK_SIZE: usize = 64 * 1024;
+
+// everything a connection keeps between two frames
+struct ConnectionState {
+    fragment_handler: FragmentHandler,
+    deflate_context: Option<DeflateContext>,
+    heartbeat: Heartbeat,
+    max_message_size: usize,
+    handler: HandlerKind,
+    codec: Arc<dyn MessageCodec>,
+    frame_timeout: Duration,
+    // reset to the deadline of the frame being received
+    frame_deadline: Pin<Box<Sleep>>,
+    idle_timeout: Duration,
+    last_message_at: Instant
+}
+
+// read_buffer holds bytes which arrived with the handshake request
+pub async fn serve_websocket_connection<R, W>(read_half: R, write_half: W, handshake_details: HandshakeDetails, read_buffer: BytesMut)
+    where R: AsyncRead + Unpin, W: AsyncWrite + Unpin {
+    let user_id = handshake_details.user_id;
+    // released when the connection is done
+    let _admission_permit = handshake_details.admission_permit;
+    let route_match = handshake_details.route_match;
+    let size_limits = route_match.size_limits();
+    // a token sent as a query parameter is not logged
+    let mut logged_query_params = route_match.query_params.clone();
+    if let AuthenticationConfig::Jwt(jwt_config) = &SERVICE_CONFIG.authentication {
+        logged_query_params.remove(&jwt_config.token_query_param);
+    }
+    crate::info!("Serving route: {}, path params: {:?}, query params: {:?}, client: {}",route_match.route.path,route_match.path_params,logged_query_params,handshake_details.client_ip);
+    // a subprotocol decides how messages are handled, otherwise the route does
+    let (handler, codec) = match &handshake_details.sub_protocol {
+        Some(sub_protocol) => (sub_protocol.handler, sub_protocol.codec.clone()),
+        None => (route_match.route.handler, sub_protocol::default_codec())
+    };
+    let validation_rules = FrameValidationRules {
+        masking: Masking::Required,
+        rsv1_allowed: handshake_details.deflate_params.is_some(),
+        max_frame_size: size_limits.max_frame_size
+    };
+    let read_half = PrefixedReader::new(read_buffer, read_half);
+    let mut frame_decoder = FrameDecoder::new(validation_rules, ReadFrom::Socket);
+    if let (Some(streaming), HandlerKind::Echo) = (route_match.route.streaming, handler) {
+        frame_decoder = frame_decoder.with_streaming(streaming);
+    }
+    let mut frame_reader = FramedRead::new(read_half, frame_decoder);
+    let mut frame_writer = FramedWrite::new(write_half, FrameEncoder::new());
+    let mut connection_state = ConnectionState {
+        fragment_handler: FragmentHandler::new(size_limits.max_message_size),
+        deflate_context: handshake_details.deflate_params.as_ref().map(|deflate_params| {
+            DeflateContext::new(deflate_params, &SERVICE_CONFIG.permessage_deflate)
+        }),
+        heartbeat: Heartbeat::new(&SERVICE_CONFIG.heartbeat),
+        max_message_size: size_limits.max_message_size,
+        handler,
+        codec,
+        frame_timeout: Duration::from_secs(SERVICE_CONFIG.timeouts.frame_timeout_secs),
+        frame_deadline: Box::pin(tokio::time::sleep(Duration::ZERO)),
+        idle_timeout: Duration::from_secs(SERVICE_CONFIG.timeouts.idle_timeout_secs),
+        last_message_at: Instant::now()
+    };
+
+    let (tx, mut rx) = mpsc::channel(100);
+    USER_ID_MAPPING.lock().await.insert(user_id.clone(),tx);
+    if SERVICE_CONFIG.cluster_mode {
+        REDIS_CLIENT.lock().await.as_mut().unwrap().set(user_id.clone(),TCP_WORKER_ADDRESS.to_ascii_lowercase()).unwrap();
+    }
+
+    let close_error = serve_frames(&mut frame_reader, &mut frame_writer, &mut rx, &mut connection_state).await;
+
+    if let Err(e) = close_error {
+        crate::error!("Closing connection: {}",e);
+        let close_handshake_complete = match e.close_code() {
+            Some(close_code) => close_connection(close_code, e.message(), &mut frame_reader, &mut frame_writer).await,
+            None => false
+        };
+        if !close_handshake_complete {
+            crate::info!("Connection closed abnormally, status: {}",CloseCode::AbnormalClosure.as_u16());
+        }
+    }
+    crate::info!("Last round trip time: {:?}",connection_state.heartbeat.last_round_trip_time);
+
+    crate::info!("Removing entry from hashMap");
+    USER_ID_MAPPING.lock().await.remove(&user_id);
+    if SERVICE_CONFIG.cluster_mode {
+        crate::info!("Removing entry from Redis: End");
+        if let Err(e) = REDIS_CLIENT.lock().await.as_mut().unwrap().delete(user_id) {
+            crate::error!("Not able to remove entry from Redis: {}",e);
+        }
+    }
+}
+
+// returns Ok once the client has closed the connection with a Close frame
+async fn serve_frames<R, W>(
+    frame_reader: &mut FramedRead<R, FrameDecoder>,
+    frame_writer: &mut FramedWrite<W, FrameEncoder>,
+    rx: &mut Receiver<DataFrameInfo>,
+    connection_state: &mut ConnectionState
+) -> Result<()> where R: AsyncRead + Unpin, W: AsyncWrite + Unpin {
+    loop {
+        let heartbeat = &mut connection_state.heartbeat;
+        let idle_timeout_enabled = !connection_state.idle_timeout.is_zero();
+        let idle_deadline = connection_state.last_message_at + connection_state.idle_timeout;
+        let data_frame = tokio::select! {
+            val = next_frame(frame_reader, connection_state.frame_timeout, &mut connection_state.frame_deadline) => match val {
+                Some(data_frame) => data_frame?,
+                None => return Err(DataFrameError::new(ErrorKind::ConnectionDropped, "connection closed without close frame"))
+            },
+            Some(data_frame) = rx.recv() => data_frame,
+            _ = tokio::time::sleep_until(heartbeat.next_deadline()), if heartbeat.is_enabled() => {
+                match heartbeat.on_deadline() {
+                    HeartbeatAction::SendPing(ping_payload) => {
+                        frame_writer.send(data_frame::new_dataframe(Opcode::Ping, ping_payload)).await?;
+                        continue;
+                    }
+                    HeartbeatAction::PongTimedOut => {
+                        return Err(DataFrameError::new(ErrorKind::PongTimeout, "pong not received"));
+                    }
+                }
+            }
+            _ = tokio::time::sleep_until(idle_deadline), if idle_timeout_enabled => {
+                crate::error!("No message sent or received in {:?}",connection_state.idle_timeout);
+                return Err(DataFrameError::new(ErrorKind::IdleTimeout, "connection idle"));
+            }
+        };
+        if !data_frame.opcode.is_control_frame() {
+            connection_state.last_message_at = Instant::now();
+        }
+
+        match data_frame.read_from {
+            ReadFrom::Socket if data_frame.payload_streamed => {
+                if connection_state.fragment_handler.is_message_in_progress() {
+                    return Err(DataFrameError::new(ErrorKind::ExpectedContinuationFrame, "binary frame received in the middle of a fragmented message"));
+                }
+                echo_streamed_frame(&data_frame, frame_reader, frame_writer, connection_state.frame_timeout).await?;
+                connection_state.last_message_at = Instant::now();
+            }
+            ReadFrom::Socket => {
+                // fragments are only sent by the client, frames from channel always carry a complete message
+                let mut data_frame = match connection_state.fragment_handler.process_frame(data_frame)? {
+                    Some(message) => message,
+                    None => continue
+                };
+                if data_frame.rs1_bit_set {
+                    decompress_message(&mut data_frame, &mut connection_state.deflate_context, connection_state.max_message_size)?;
+                }
+                if data_frame.opcode == Opcode::ConnectionClose {
+                    crate::info!("Close Connection Opcode received");
+                    data_frame::parse_close_payload(&mut data_frame)?;
+                    reply_to_close_frame(&data_frame, frame_writer).await;
+                    return Ok(());
+                }
+                process_frame(data_frame, frame_writer, connection_state).await?;
+            }
+            ReadFrom::Channel => {
+                let data_frame = encode_message(data_frame, connection_state.codec.as_ref());
+                send_reply_arrived_to_this_user(data_frame, frame_writer, &mut connection_state.deflate_context).await?
+            }
+        }
+    }
+}
+
+// next frame of the client. Once the first bytes of a frame have arrived the
+// rest has to follow within frame_timeout, a peer trickling a frame in does
+// not get to hold the connection.
+async fn next_frame<R>(frame_reader: &mut FramedRead<R, FrameDecoder>, frame_timeout: Duration, frame_deadline: &mut Pin<Box<Sleep>>) -> Option<Result<DataFrameInfo>>
+    where R: AsyncRead + Unpin {
+    poll_fn(|cx| {
+        if let Poll::Ready(data_frame) = frame_reader.poll_next_unpin(cx) {
+            return Poll::Ready(data_frame);
+        }
+        // the decoder has seen everything read so far
+        let frame_started_at = match frame_reader.decoder().frame_started_at() {
+            Some(frame_started_at) if !frame_timeout.is_zero() => frame_started_at,
+            _ => return Poll::Pending
+        };
+        let deadline = frame_started_at + frame_timeout;
+        if frame_deadline.deadline() != deadline {
+            frame_deadline.as_mut().reset(deadline);
+        }
+        match frame_deadline.as_mut().poll(cx) {
+            Poll::Ready(()) => {
+                crate::error!("Frame not complete {:?} after its first bytes",frame_timeout);
+                Poll::Ready(Some(Err(DataFrameError::new(ErrorKind::FrameTimeout, "frame not received in time"))))
+            }
+            Poll::Pending => Poll::Pending
+        }
+    }).await
+}
+
+// the payload is written back as it arrives, the frame is never held in memory
+// as a whole. Once the header is sent a failure can only drop the connection,
+// a Close frame would land in the middle of the payload.
+async fn echo_streamed_frame<R, W>(data_frame: &DataFrameInfo, frame_reader: &mut FramedRead<R, FrameDecoder>, frame_writer: &mut FramedWrite<W, FrameEncoder>, frame_timeout: Duration) -> Result<()>
+    where R: AsyncRead + Unpin, W: AsyncWrite + Unpin {
+    crate::info!("Streaming back binary frame of {} bytes",data_frame.payload_length);
+    frame_writer.flush().await?;
+    let write_half = frame_writer.get_mut();
+    write_half.write_all(&payload_reader::unmasked_frame_header(Opcode::BinaryFrame, data_frame.payload_length)).await?;
+    let mut payload_reader = PayloadReader::new(frame_reader, data_frame);
+    let mut chunk = vec![0u8; STREAM_CHUNK_SIZE.min(data_frame.payload_length)];
+    while payload_reader.bytes_left() > 0 {
+        let bytes_read = if frame_timeout.is_zero() {
+            payload_reader.read(&mut chunk).await?
+        } else {
+            match tokio::time::timeout(frame_timeout, payload_reader.read(&mut chunk)).await {
+                Ok(bytes_read) => bytes_read?,
+                Err(_) => return Err(DataFrameError::new(ErrorKind::ConnectionDropped, "streamed payload not received in time"))
+            }
+        };
+        write_half.write_all(&chunk[..bytes_read]).await?;
+    }
+    write_half.flush().await?;
+    crate::info!("Streamed frame sent back");
+    Ok(())
+}
+
+fn decompress_message(data_frame: &mut DataFrameInfo, deflate_context: &mut Option<DeflateContext>, max_message_size: usize) -> Result<()> {
+    match deflate_context.as_mut() {
+        Some(deflate_context) => deflate_context.decompress_message(data_frame, max_message_size)?,
+        None => return Err(DataFrameError::new(ErrorKind::ProtocolError, "compressed message without permessage-deflate"))
+    }
+    if data_frame.opcode == Opcode::TextFrame {
+        utf8_validator::validate_utf8(&data_frame.payload_data)?;
+    }
+    Ok(())
+}
+
+async fn process_frame<W>(data_frame: DataFrameInfo, frame_writer: &mut FramedWrite<W, FrameEncoder>, connection_state: &mut ConnectionState) -> Result<()>
+    where W: AsyncWrite + Unpin {
+    match data_frame.opcode {
+        Opcode::TextFrame | Opcode::BinaryFrame => match connection_state.handler {
+            HandlerKind::Chat => route_message(data_frame, connection_state.codec.as_ref()).await,
+            HandlerKind::Echo => send_reply_arrived_to_this_user(data_frame, frame_writer, &mut connection_state.deflate_context).await
+        },
+        Opcode::Ping => {
+            frame_writer.send(data_frame::new_dataframe(Opcode::Pong, data_frame.payload_data)).await
+        }
+        Opcode::Pong => {
+            connection_state.heartbeat.pong_received(&data_frame.payload_data);
+            Ok(())
+        }
+        _ => {
+            crate::error!("Opcode not supported");
+            Err(DataFrameError::new(ErrorKind::InvalidOpcode, "opcode not supported"))
+        }
+    }
+}
+
+// message is delivered to the user it names, on this service or the one the user is connected to
+async fn route_message(mut data_frame: DataFrameInfo, codec: &dyn MessageCodec) -> Result<()> {
+    let message: Message = match codec.decode(&data_frame.payload_data) {
+        Ok(message) => message,
+        Err(e) => return Err(DataFrameError::new(ErrorKind::UnsupportedData, e))
+    };
+    // messages are passed on in the v1 format whatever the subprotocol of the sender
+    data_frame.payload_data = serde_json::to_vec(&message).unwrap();
+    let tx2 = USER_ID_MAPPING.lock().await.get(&message.sender_user_id).cloned();
+    match tx2 {
+        None => send_dataframe_to_other_service(message,data_frame).await,
+        Some(tx2) => channel_handler::send_dataframe_to_channel(data_frame,&tx2).await
+    }
+    Ok(())
+}
+
+// message arrived in the v1 format, written in the one of this connection
+fn encode_message(mut data_frame: DataFrameInfo, codec: &dyn MessageCodec) -> DataFrameInfo {
+    match serde_json::from_slice::<Message>(&data_frame.payload_data) {
+        Ok(message) => data_frame.payload_data = codec.encode(&message),
+        Err(e) => crate::error!("Message from channel is not in v1 format, sent as it is: {}",e)
+    }
+    data_frame
+}
+
+async fn send_dataframe_to_other_service(message: Message, data_frame: DataFrameInfo) {
+    if SERVICE_CONFIG.cluster_mode {
+        crate::info!("User not connected to this server");
+        let ip: String = REDIS_CLIENT.lock().await.as_mut().unwrap().get(&message.sender_user_id).unwrap_or_else(|_err| {
+            "".to_string()
+        });
+        crate::info!("User connected to the server: {}",ip);
+
+        if !ip.is_empty() {
+            workers::tcp_message_transmitter::transmit(data_frame, ip).await;
+        } else {
+            crate::error!("User Not Connected to any Service")
+        }
+    } else {
+        crate::info!("User Not Connected to this Service")
+    }
+}
+
+async fn send_reply_arrived_to_this_user<W>(mut data_frame: DataFrameInfo, frame_writer: &mut FramedWrite<W, FrameEncoder>, deflate_context: &mut Option<DeflateContext>) -> Result<()>
+    where W: AsyncWrite + Unpin {
+    crate::info!("Sending reply arrived for this user");
+    if let Some(deflate_context) = deflate_context {
+        if deflate_context.should_compress(data_frame.payload_data.len()) {
+            deflate_context.compress_dataframe(&mut data_frame)?;
+        }
+    }
+    frame_writer.send(data_frame).await?;
+    crate::info!("Reply Sent");
+    Ok(())
+}
+
+//  If an endpoint receives a Close frame and did not previously send a
+//    Close frame, the endpoint MUST send a Close frame in response.  (When
+//    sending a Close frame in response, the endpoint typically echos the
+//    status code it received.)
+async fn reply_to_close_frame<W>(data_frame: &DataFrameInfo, frame_writer: &mut FramedWrite<W, FrameEncoder>)
+    where W: AsyncWrite + Unpin {
+    crate::info!("Client closed connection, code: {:?}, reason: {}",data_frame.close_code,data_frame.close_reason);
+    let close_frame = data_frame::create_close_dataframe(data_frame.close_code, "");
+    if let Err(e) = frame_writer.send(close_frame).await {
+        crate::error!("Not able to send close frame: {}",e);
+    }
+}
+
+// server initiated closing handshake, after sending the Close frame we wait for the
+// client to reply with its own Close frame before dropping the connection.
+// returns true if the client replied with its Close frame
+async fn close_connection<R, W>(close_code: CloseCode, close_reason: &str, frame_reader: &mut FramedRead<R, FrameDecoder>, frame_writer: &mut FramedWrite<W, FrameEncoder>) -> bool
+    where R: AsyncRead + Unpin, W: AsyncWrite + Unpin {
+    crate::info!("Sending close frame, code: {:?}",close_code);
+    let close_frame = data_frame::create_close_dataframe(Some(close_code.as_u16()), close_reason);
+    if let Err(e) = frame_writer.send(close_frame).await {
+        crate::error!("Not able to send close frame: {}",e);
+        return false;
+    }
+    let wait_for_close_frame = async {
+        loop {
+            match frame_reader.next().await {
+                Some(Ok(data_frame)) if data_frame.opcode == Opcode::ConnectionClose => {
+                    crate::info!("Closing handshake complete");
+                    return true;
+                }
+                // any other frame received after sending Close frame is discarded
+                Some(Ok(_)) => {}
+                _ => return false
+            }
+        }
+    };
+    match tokio::time::timeout(CLOSE_HANDSHAKE_TIMEOUT, wait_for_close_frame).await {
+        Ok(close_handshake_complete) => close_handshake_complete,
+        Err(_) => {
+            crate::error!("Client did not reply to close frame in {:?}",CLOSE_HANDSHAKE_TIMEOUT);
+            false
+        }
+    }
+}
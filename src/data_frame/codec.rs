@@ -0,0 +1,319 @@
+// Frame parsing without any IO, works on whatever bytes have arrived so far.
+// FramedRead/FramedWrite from tokio_util run it over any AsyncRead/AsyncWrite,
+// a tcp socket, a tls stream or an in memory duplex stream.
+
+use bytes::{Buf, BufMut, BytesMut};
+use tokio::time::Instant;
+use tokio_util::codec::{Decoder, Encoder};
+use crate::error::data_frame_error::{DataFrameError, ErrorKind, Result};
+use crate::service_config::StreamingConfig;
+use super::{
+    DataFrameInfo, FrameValidationRules, Opcode, ReadFrom, FIN_BITMASK, RSV1_BITMASK, WEBSOCKET_MASK_BITMASK,
+    calculate_payload_length, extract_mask_key, get_default_dataframe, get_opcode_bits,
+    get_payload_length_bits, mask_unmask_data, process_data_frame_first_byte,
+    process_data_frame_second_byte, validate_frame_header
+};
+
+pub enum DecodeResult {
+    // the frame and the number of bytes it took from the buffer, for a header
+    // decoded on its own that is the header length
+    Frame(DataFrameInfo, usize),
+    // at least this many more bytes are needed before the frame can be parsed
+    NeedMoreBytes(usize)
+}
+
+/*
+     0                   1                   2                   3
+      0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1
+     +-+-+-+-+-------+-+-------------+-------------------------------+
+     |F|R|R|R| opcode|M| Payload len |    Extended payload length    |
+     |I|S|S|S|  (4)  |A|     (7)     |             (16/64)           |
+     |N|V|V|V|       |S|             |   (if payload len==126/127)   |
+     | |1|2|3|       |K|             |                               |
+     +-+-+-+-+-------+-+-------------+ - - - - - - - - - - - - - - - +
+     |     Extended payload length continued, if payload len == 127  |
+     + - - - - - - - - - - - - - - - +-------------------------------+
+     |                               |Masking-key, if MASK set to 1  |
+     +-------------------------------+-------------------------------+
+     | Masking-key (continued)       |          Payload Data         |
+     +-------------------------------- - - - - - - - - - - - - - - - +
+     :                     Payload Data continued ...                :
+     + - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - +
+     |                     Payload Data continued ...                |
+     +---------------------------------------------------------------+
+ */
+pub struct FrameDecoder {
+    validation_rules: FrameValidationRules,
+    read_from: ReadFrom,
+    // when the first bytes of the frame being waited for arrived
+    frame_started_at: Option<Instant>,
+    streaming: Option<StreamingConfig>
+}
+
+impl FrameDecoder {
+    pub fn new(validation_rules: FrameValidationRules, read_from: ReadFrom) -> FrameDecoder {
+        FrameDecoder {
+            validation_rules,
+            read_from,
+            frame_started_at: None,
+            streaming: None
+        }
+    }
+
+    // frames picked by the streaming config are returned with only their
+    // header, the payload is read with a PayloadReader
+    pub fn with_streaming(mut self, streaming: StreamingConfig) -> FrameDecoder {
+        self.streaming = Some(streaming);
+        self
+    }
+
+    // None while no byte of the next frame has arrived
+    pub fn frame_started_at(&self) -> Option<Instant> {
+        self.frame_started_at
+    }
+
+    // parses the frame at the start of src, src is left untouched
+    pub fn decode_frame(&self, src: &[u8]) -> Result<DecodeResult> {
+        let max_payload_size = match self.streaming {
+            Some(streaming) => streaming.max_frame_size.max(self.validation_rules.max_frame_size),
+            None => self.validation_rules.max_frame_size
+        };
+        let (mut data_frame_info, header_length) = match self.decode_header(src, max_payload_size)? {
+            DecodeResult::Frame(data_frame_info, header_length) => (data_frame_info, header_length),
+            DecodeResult::NeedMoreBytes(bytes_needed) => return Ok(DecodeResult::NeedMoreBytes(bytes_needed))
+        };
+        if self.is_streamed(&data_frame_info) {
+            data_frame_info.payload_streamed = true;
+            return Ok(DecodeResult::Frame(data_frame_info, header_length));
+        }
+        if data_frame_info.payload_length > self.validation_rules.max_frame_size {
+            crate::error!("Frame payload length: {} is more than the limit: {}",data_frame_info.payload_length,self.validation_rules.max_frame_size);
+            return Err(DataFrameError::new(ErrorKind::MessageTooBig, "frame payload is more than the limit"));
+        }
+
+        let frame_length = match header_length.checked_add(data_frame_info.payload_length) {
+            Some(frame_length) => frame_length,
+            None => return Err(DataFrameError::new(ErrorKind::MessageTooBig, "frame length overflows"))
+        };
+        if src.len() < frame_length {
+            return Ok(DecodeResult::NeedMoreBytes(frame_length - src.len()));
+        }
+        data_frame_info.payload_data = src[header_length..frame_length].to_vec();
+        Ok(DecodeResult::Frame(data_frame_info, frame_length))
+    }
+
+    fn is_streamed(&self, data_frame_info: &DataFrameInfo) -> bool {
+        match self.streaming {
+            Some(streaming) => data_frame_info.opcode == Opcode::BinaryFrame
+                && data_frame_info.is_this_final_frame
+                && !data_frame_info.rs1_bit_set
+                && data_frame_info.payload_length >= streaming.min_frame_size,
+            None => false
+        }
+    }
+
+    // parses only the header of the frame at the start of src, the frame is returned
+    // without payload along with the length of the header. Payload is allowed to be
+    // up to max_payload_size instead of the frame size limit
+    pub fn decode_header(&self, src: &[u8], max_payload_size: usize) -> Result<DecodeResult> {
+        if src.len() < 2 {
+            return Ok(DecodeResult::NeedMoreBytes(2 - src.len()));
+        }
+        let mut data_frame_info = get_default_dataframe();
+        data_frame_info.read_from = self.read_from;
+        process_data_frame_first_byte(&mut data_frame_info,src[0]);
+        process_data_frame_second_byte(&mut data_frame_info,src[1]);
+
+        let mut header_length: usize = 2;
+        if data_frame_info.payload_length_field == 126 {
+            header_length += 2;
+        }
+        if data_frame_info.payload_length_field == 127 {
+            header_length += 8;
+        }
+        if data_frame_info.contain_masked_data {
+            header_length += 4;
+        }
+        if src.len() < header_length {
+            return Ok(DecodeResult::NeedMoreBytes(header_length - src.len()));
+        }
+        data_frame_info.raw_bytes.extend_from_slice(&src[2..header_length]);
+        calculate_payload_length(&mut data_frame_info);
+        extract_mask_key(&mut data_frame_info);
+        let validation_rules = FrameValidationRules {
+            max_frame_size: max_payload_size,
+            ..self.validation_rules
+        };
+        validate_frame_header(&data_frame_info, &validation_rules)?;
+        Ok(DecodeResult::Frame(data_frame_info, header_length))
+    }
+}
+
+impl Decoder for FrameDecoder {
+    type Item = DataFrameInfo;
+    type Error = DataFrameError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<DataFrameInfo>> {
+        match self.decode_frame(src)? {
+            DecodeResult::Frame(data_frame_info, frame_length) => {
+                src.advance(frame_length);
+                self.frame_started_at = None;
+                Ok(Some(data_frame_info))
+            }
+            DecodeResult::NeedMoreBytes(bytes_needed) => {
+                src.reserve(bytes_needed);
+                if !src.is_empty() && self.frame_started_at.is_none() {
+                    self.frame_started_at = Some(Instant::now());
+                }
+                Ok(None)
+            }
+        }
+    }
+}
+
+// writes frames as they are, payload is masked if the frame says so
+#[derive(Default)]
+pub struct FrameEncoder {
+    mask_frames: bool
+}
+
+impl FrameEncoder {
+    pub fn new() -> FrameEncoder {
+        FrameEncoder {
+            mask_frames: false
+        }
+    }
+
+    //  A client MUST mask all frames that it sends to the server.
+    //
+    //  The masking key is a 32-bit value chosen at random by the client.
+    //    When preparing a masked frame, the client MUST pick a fresh masking
+    //    key from the set of allowed 32-bit values.
+    #[cfg(test)]
+    pub fn new_client() -> FrameEncoder {
+        FrameEncoder {
+            mask_frames: true
+        }
+    }
+}
+
+impl Encoder<DataFrameInfo> for FrameEncoder {
+    type Error = DataFrameError;
+
+    fn encode(&mut self, mut data_frame_info: DataFrameInfo, dst: &mut BytesMut) -> Result<()> {
+        if self.mask_frames {
+            data_frame_info.contain_masked_data = true;
+            data_frame_info.mask_key = rand::random::<[u8;4]>();
+        }
+        let mut first_byte = get_opcode_bits(data_frame_info.opcode);
+        if data_frame_info.is_this_final_frame {
+            first_byte |= FIN_BITMASK;
+        }
+        if data_frame_info.rs1_bit_set {
+            first_byte |= RSV1_BITMASK;
+        }
+        let masked_bit = if data_frame_info.contain_masked_data { WEBSOCKET_MASK_BITMASK } else { 0 };
+        let payload_length_bits = get_payload_length_bits(data_frame_info.payload_data.len(), masked_bit);
+
+        dst.reserve(1 + payload_length_bits.len() + 4 + data_frame_info.payload_data.len());
+        dst.put_u8(first_byte);
+        dst.extend_from_slice(&payload_length_bits);
+        if data_frame_info.contain_masked_data {
+            dst.extend_from_slice(&data_frame_info.mask_key);
+            let payload_start = dst.len();
+            dst.extend_from_slice(&data_frame_info.payload_data);
+            mask_unmask_data(&mut dst[payload_start..], &data_frame_info.mask_key);
+        } else {
+            dst.extend_from_slice(&data_frame_info.payload_data);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::{SinkExt, StreamExt};
+    use tokio_util::codec::{FramedRead, FramedWrite};
+    use crate::data_frame::{CloseCode, Masking, Opcode, new_dataframe, unmask_payload};
+    use super::*;
+
+    fn server_decoder(max_frame_size: usize) -> FrameDecoder {
+        let validation_rules = FrameValidationRules {
+            masking: Masking::Required,
+            rsv1_allowed: false,
+            max_frame_size
+        };
+        FrameDecoder::new(validation_rules, ReadFrom::Socket)
+    }
+
+    #[test]
+    fn partial_header_needs_more_bytes() {
+        let decoder = server_decoder(1024);
+        assert!(matches!(decoder.decode_frame(&[0x81]), Ok(DecodeResult::NeedMoreBytes(1))));
+        // masked frame with a 16-bit length, the length and the mask key are missing
+        assert!(matches!(decoder.decode_frame(&[0x82, 0xfe, 0x01]), Ok(DecodeResult::NeedMoreBytes(5))));
+    }
+
+    #[test]
+    fn frame_over_max_size_is_refused_from_its_header() {
+        let decoder = server_decoder(1024);
+        let mut header = vec![0x82, 0xff];
+        header.extend_from_slice(&(u64::MAX >> 1).to_be_bytes());
+        header.extend_from_slice(&[1, 2, 3, 4]);
+        // no payload byte is there, the length alone is enough to refuse it
+        match decoder.decode_frame(&header) {
+            Err(e) => assert_eq!(e.close_code(), Some(CloseCode::MessageTooBig)),
+            Ok(_) => panic!("frame over max_frame_size accepted")
+        }
+    }
+
+    #[test]
+    fn length_with_the_most_significant_bit_set_is_refused() {
+        // the limit would refuse it too, but it is a protocol error and not a too big message
+        let decoder = server_decoder(1024);
+        let mut header = vec![0x82, 0xff];
+        header.extend_from_slice(&(1u64 << 63).to_be_bytes());
+        header.extend_from_slice(&[1, 2, 3, 4]);
+        match decoder.decode_frame(&header) {
+            Err(e) => assert_eq!(e.close_code(), Some(CloseCode::ProtocolError)),
+            Ok(_) => panic!("64-bit length with the most significant bit set accepted")
+        }
+    }
+
+    #[test]
+    fn reserved_opcode_is_refused() {
+        let decoder = server_decoder(1024);
+        match decoder.decode_frame(&[0x83, 0x80, 1, 2, 3, 4]) {
+            Err(e) => assert_eq!(e.close_code(), Some(CloseCode::ProtocolError)),
+            Ok(_) => panic!("reserved opcode accepted")
+        }
+    }
+
+    #[test]
+    fn unmasked_client_frame_is_refused() {
+        let decoder = server_decoder(1024);
+        match decoder.decode_frame(&[0x81, 0x02, b'h', b'i']) {
+            Err(e) => assert_eq!(e.close_code(), Some(CloseCode::ProtocolError)),
+            Ok(_) => panic!("unmasked frame accepted")
+        }
+    }
+
+    #[tokio::test]
+    async fn masked_frame_round_trip_over_duplex() {
+        let (client, server) = tokio::io::duplex(64);
+        let mut frame_writer = FramedWrite::new(client, FrameEncoder::new_client());
+        let mut frame_reader = FramedRead::new(server, server_decoder(1024));
+        // bigger than the duplex buffer, the frame arrives in pieces
+        let payload: Vec<u8> = (0..300).map(|i| (i % 251) as u8).collect();
+        let send = frame_writer.send(new_dataframe(Opcode::BinaryFrame, payload.clone()));
+        let (sent, received) = tokio::join!(send, frame_reader.next());
+        sent.unwrap();
+        let mut data_frame = received.unwrap().unwrap();
+        assert!(data_frame.contain_masked_data);
+        // the payload is left masked, it is unmasked with the fragments
+        unmask_payload(&mut data_frame);
+        assert!(data_frame.is_this_final_frame);
+        assert_eq!(data_frame.opcode, Opcode::BinaryFrame);
+        assert_eq!(data_frame.payload_data, payload);
+    }
+}
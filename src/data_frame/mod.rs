@@ -5,12 +5,11 @@
 
 // read frames in to text
 
-use std::io::Read;
-use crate::buffer::Buffer;
-use crate::{tcp_handler, channel_handler};
-use tokio::net::tcp::OwnedReadHalf;
-use tokio::io::{AsyncReadExt, ReadBuf};
-use tokio::sync::mpsc::Receiver;
+use std::convert::TryFrom;
+use crate::error::data_frame_error::{DataFrameError, ErrorKind, Result};
+
+pub mod codec;
+pub mod payload_reader;
 
 static FIN_BITMASK: u8 =  0b10000000;
 static RSV1_BITMASK: u8 = 0b01000000;
@@ -20,7 +19,7 @@ static OPCODE_BITMASK: u8 = 0b00001111;
 static WEBSOCKET_MASK_BITMASK: u8 = 0b10000000;
 static INITIAL_PAYLOAD_LENGTH_MASK: u8 = 0b01111111;
 
-#[derive(Copy, Clone,Debug)]
+#[derive(Copy, Clone,Debug,PartialEq)]
 pub enum Opcode {
     ContinuationFrame,
     TextFrame,
@@ -31,6 +30,36 @@ pub enum Opcode {
     NoOpcodeFound
 }
 
+impl Opcode {
+    // Control frames are identified by opcodes where the most significant
+    //    bit of the opcode is 1
+    pub fn is_control_frame(&self) -> bool {
+        matches!(self, Opcode::ConnectionClose | Opcode::Ping | Opcode::Pong)
+    }
+}
+
+//  Endpoints MAY use the following pre-defined status codes when sending
+//    a Close frame.
+#[derive(Copy, Clone,Debug,PartialEq)]
+pub enum CloseCode {
+    GoingAway = 1001,
+    ProtocolError = 1002,
+    UnsupportedData = 1003,
+    // reported when the connection was closed without receiving a Close frame,
+    // never sent on the wire
+    AbnormalClosure = 1006,
+    InvalidPayload = 1007,
+    PolicyViolation = 1008,
+    MessageTooBig = 1009,
+    InternalError = 1011
+}
+
+impl CloseCode {
+    pub fn as_u16(&self) -> u16 {
+        *self as u16
+    }
+}
+
 #[derive(Copy, Clone,Debug)]
 pub enum ReadFrom{
     Socket,
@@ -46,7 +75,7 @@ static OPCODES_ARRAY: [(u8,Opcode);6] = [
     (0b00001010,Opcode::Pong) // denotes a pong
 ];
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct DataFrameInfo {
     pub mask_key: [u8;4],
     pub contain_masked_data: bool,
@@ -60,11 +89,10 @@ pub struct DataFrameInfo {
     pub rs1_bit_set: bool,
     pub rs2_bit_set: bool,
     pub rs3_bit_set: bool,
-}
-
-
-impl DataFrameInfo {
-    
+    pub close_code: Option<u16>,
+    pub close_reason: String,
+    // only the header was decoded, the payload is left to be streamed
+    pub payload_streamed: bool
 }
 
 pub fn get_default_dataframe() -> DataFrameInfo {
@@ -81,149 +109,92 @@ pub fn get_default_dataframe() -> DataFrameInfo {
         rs1_bit_set: false,
         rs2_bit_set: false,
         rs3_bit_set: false,
+        close_code: None,
+        close_reason: String::new(),
+        payload_streamed: false
     }
 }
 
-// only supported on 64bit server
-pub fn parse_data_to_string(data_frame_info: &DataFrameInfo, data: &mut [u8]) -> String {
-    let str_res = "";
-
-    if data_frame_info.contain_masked_data {
-        mask_unmask_data(data,&data_frame_info.mask_key)
-    }
-
-    let str_res = std::str::from_utf8(data).unwrap();
-
-    str_res.to_string()
+//  The server MUST close the connection upon receiving a
+//    frame that is not masked.
+//
+//  A client MUST close a connection if it detects a masked
+//    frame.
+#[derive(Copy, Clone, PartialEq)]
+pub enum Masking {
+    // frames sent by a client
+    Required,
+    // frames sent by a server
+    Forbidden,
+    // frames forwarded between services
+    Optional
 }
 
-/*
-     0                   1                   2                   3
-      0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1
-     +-+-+-+-+-------+-+-------------+-------------------------------+
-     |F|R|R|R| opcode|M| Payload len |    Extended payload length    |
-     |I|S|S|S|  (4)  |A|     (7)     |             (16/64)           |
-     |N|V|V|V|       |S|             |   (if payload len==126/127)   |
-     | |1|2|3|       |K|             |                               |
-     +-+-+-+-+-------+-+-------------+ - - - - - - - - - - - - - - - +
-     |     Extended payload length continued, if payload len == 127  |
-     + - - - - - - - - - - - - - - - +-------------------------------+
-     |                               |Masking-key, if MASK set to 1  |
-     +-------------------------------+-------------------------------+
-     | Masking-key (continued)       |          Payload Data         |
-     +-------------------------------- - - - - - - - - - - - - - - - +
-     :                     Payload Data continued ...                :
-     + - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - - +
-     |                     Payload Data continued ...                |
-     +---------------------------------------------------------------+
- */
-pub async fn read_next_dataframe_from_socket(read_half: &mut OwnedReadHalf) -> DataFrameInfo {
-    let mut data_frame_info = DataFrameInfo {
-        mask_key: [0;4],
-        payload_length: 0,
-        payload_length_field: 0,
-        opcode: Opcode::NoOpcodeFound,
-        is_this_final_frame: false,
-        contain_masked_data: false,
-        read_from: ReadFrom::Socket,
-        raw_bytes: Vec::new(),
-        payload_data: Vec::new(),
-        rs1_bit_set: false,
-        rs2_bit_set: false,
-        rs3_bit_set: false,
-    };
-    let first_byte = read_half.read_u8().await.unwrap();
-    process_data_frame_first_byte(&mut data_frame_info,first_byte);
-
-    let second_byte = read_half.read_u8().await.unwrap();
-    process_data_frame_second_byte(&mut data_frame_info,second_byte);
+// what the peer is allowed to send on a connection
+#[derive(Copy, Clone)]
+pub struct FrameValidationRules {
+    pub masking: Masking,
+    // RSV1 is owned by permessage-deflate once it is negotiated
+    pub rsv1_allowed: bool,
+    // frames with a bigger payload are refused before the payload is read
+    pub max_frame_size: usize
+}
 
-    if data_frame_info.payload_length_field == 126 {
-        data_frame_info.raw_bytes.append(&mut tcp_handler::read_specified_bytes_from_socket(read_half, 2).await.unwrap());
+//  MUST be 0 unless an extension is negotiated that defines meanings
+//    for non-zero values.  If a nonzero value is received and none of
+//    the negotiated extensions defines the meaning of such a nonzero
+//    value, the receiving endpoint MUST _Fail the WebSocket
+//    Connection_.
+//
+// checked as soon as the header is read so nothing is allocated for an invalid frame
+pub fn validate_frame_header(data_frame_info: &DataFrameInfo, validation_rules: &FrameValidationRules) -> Result<()> {
+    let opcode = data_frame_info.opcode;
+    if opcode == Opcode::NoOpcodeFound {
+        return Err(DataFrameError::new(ErrorKind::InvalidOpcode, "reserved opcode used"));
     }
-    if data_frame_info.payload_length_field == 127 {
-        data_frame_info.raw_bytes.append(&mut tcp_handler::read_specified_bytes_from_socket(read_half, 8).await.unwrap());
-    }
-    calculate_payload_length(&mut data_frame_info);
 
-    if data_frame_info.contain_masked_data {
-        let mut buffer = tcp_handler::read_specified_bytes_from_socket(read_half, 4).await.unwrap();
-        data_frame_info.raw_bytes.append(&mut buffer);
+    //  All control frames MUST have a payload length of 125 bytes or less
+    //    and MUST NOT be fragmented.
+    if opcode.is_control_frame() {
+        if data_frame_info.payload_length > 125 {
+            return Err(DataFrameError::new(ErrorKind::ControlFrameTooBig, "control frame payload is more than 125 bytes"));
+        }
+        if !data_frame_info.is_this_final_frame {
+            return Err(DataFrameError::new(ErrorKind::FragmentedControlFrame, "control frame is fragmented"));
+        }
     }
-    extract_mask_key(&mut data_frame_info);
-
-    data_frame_info.payload_data = tcp_handler::read_specified_bytes_from_socket(read_half, data_frame_info.payload_length).await.unwrap();
-    data_frame_info
-}
 
-pub async fn read_dataframe_from_rx(rx: &mut Receiver<Vec<u8>>) -> DataFrameInfo {
-    let mut data_frame_info = DataFrameInfo {
-        mask_key: [0;4],
-        payload_length: 0,
-        payload_length_field: 0,
-        opcode: Opcode::NoOpcodeFound,
-        is_this_final_frame: false,
-        contain_masked_data: false,
-        read_from: ReadFrom::Channel,
-        raw_bytes: Vec::new(),
-        payload_data: Vec::new(),
-        rs1_bit_set: false,
-        rs2_bit_set: false,
-        rs3_bit_set: false,
-    };
-    let vec_dataframe_bytes = rx.recv().await.unwrap();
-    let mut ptr:usize = 0;
-    let first_byte = vec_dataframe_bytes[ptr];
-    ptr += 1;
-    process_data_frame_first_byte(&mut data_frame_info,first_byte);
+    //  If 127, the following 8 bytes interpreted as a 64-bit unsigned
+    //    integer (the most significant bit MUST be 0) are the payload length.
+    if data_frame_info.payload_length_field == 127 && data_frame_info.raw_bytes[2] & 0x80 != 0 {
+        return Err(DataFrameError::new(ErrorKind::ProtocolError, "most significant bit of the 64-bit payload length is set"));
+    }
 
-    let second_byte = vec_dataframe_bytes[ptr];
-    ptr += 1;
-    process_data_frame_second_byte(&mut data_frame_info,second_byte);
+    if data_frame_info.payload_length > validation_rules.max_frame_size {
+        crate::error!("Frame payload length: {} is more than the limit: {}",data_frame_info.payload_length,validation_rules.max_frame_size);
+        return Err(DataFrameError::new(ErrorKind::MessageTooBig, "frame payload is more than the limit"));
+    }
 
-    if data_frame_info.payload_length_field == 126 {
-        data_frame_info.raw_bytes.push(vec_dataframe_bytes[ptr]);
-        ptr += 1;
-        data_frame_info.raw_bytes.push(vec_dataframe_bytes[ptr]);
-        ptr += 1;
+    //  In this case, a server MAY send a Close frame with a status code of
+    //    1002 (protocol error)
+    if validation_rules.masking == Masking::Required && !data_frame_info.contain_masked_data {
+        return Err(DataFrameError::new(ErrorKind::UnmaskedFrame, "frame from client is not masked"));
     }
-    if data_frame_info.payload_length_field == 127 {
-        data_frame_info.raw_bytes.push(vec_dataframe_bytes[ptr]);
-        ptr += 1;
-        data_frame_info.raw_bytes.push(vec_dataframe_bytes[ptr]);
-        ptr += 1;
-        data_frame_info.raw_bytes.push(vec_dataframe_bytes[ptr]);
-        ptr += 1;
-        data_frame_info.raw_bytes.push(vec_dataframe_bytes[ptr]);
-        ptr += 1;
-        data_frame_info.raw_bytes.push(vec_dataframe_bytes[ptr]);
-        ptr += 1;
-        data_frame_info.raw_bytes.push(vec_dataframe_bytes[ptr]);
-        ptr += 1;
-        data_frame_info.raw_bytes.push(vec_dataframe_bytes[ptr]);
-        ptr += 1;
-        data_frame_info.raw_bytes.push(vec_dataframe_bytes[ptr]);
-        ptr += 1;
+    if validation_rules.masking == Masking::Forbidden && data_frame_info.contain_masked_data {
+        return Err(DataFrameError::new(ErrorKind::MaskedFrame, "frame from server is masked"));
     }
-    calculate_payload_length(&mut data_frame_info);
 
-    if data_frame_info.contain_masked_data {
-        data_frame_info.raw_bytes.push(vec_dataframe_bytes[ptr]);
-        ptr += 1;
-        data_frame_info.raw_bytes.push(vec_dataframe_bytes[ptr]);
-        ptr += 1;
-        data_frame_info.raw_bytes.push(vec_dataframe_bytes[ptr]);
-        ptr += 1;
-        data_frame_info.raw_bytes.push(vec_dataframe_bytes[ptr]);
-        ptr += 1;
+    // only the first frame of a data message can carry the compressed bit
+    let rsv1_allowed = validation_rules.rsv1_allowed && !opcode.is_control_frame() && opcode != Opcode::ContinuationFrame;
+    if (data_frame_info.rs1_bit_set && !rsv1_allowed) || data_frame_info.rs2_bit_set || data_frame_info.rs3_bit_set {
+        return Err(DataFrameError::new(ErrorKind::ReservedBitsSet, "reserved bits set without an extension owning them"));
     }
-    data_frame_info.payload_data = channel_handler::read_vector_from_channel(rx).await.unwrap();
-    data_frame_info
+    Ok(())
 }
 
 fn parse_opcode(opcode: u8) -> Opcode{
     for opcode_mapping in OPCODES_ARRAY.iter() {
-        if opcode & opcode_mapping.0 != 0 {
+        if opcode == opcode_mapping.0 {
             return opcode_mapping.1;
         }
     }
@@ -257,19 +228,6 @@ fn get_payload_length_bits(data_len: usize, mut masked_bit: u8) -> Vec<u8> {
     vec
 }
 
-pub fn create_pong_frame(data_len: usize) -> Vec<u8> {
-    let mut pong_frame = Buffer::new_unbound();
-    let pong_opcode: u8 = 0b00001010;
-    pong_frame.append_byte(0b10000000 | pong_opcode);
-    let mut masked_bit: u8 = 0;
-
-    pong_frame.append_vec8_array(
-        &get_payload_length_bits(data_len,masked_bit)
-    );
-
-    pong_frame.get_arr()
-}
-
 pub fn mask_unmask_data(data: &mut [u8], mask_key: &[u8;4]) {
     for i in 0..data.len() {
         let mut j = i % 4;
@@ -277,45 +235,81 @@ pub fn mask_unmask_data(data: &mut [u8], mask_key: &[u8;4]) {
     }
 }
 
-pub fn create_text_frame(data: &[u8]) -> Vec<u8> {
-    let mut text_frame = Buffer::new_unbound();
-    let text_opcode: u8 = 0b00000001;
-    text_frame.append_byte(0b10000000 | text_opcode);
-    let mut masked_bit: u8 = 0;
-
-    text_frame.append_vec8_array(
-        &get_payload_length_bits(data.len(),masked_bit)
-    );
-
-    text_frame.append_u8_array(data);
-
-    text_frame.get_arr()
+// unmasks the payload in place, after this the frame can be treated as unmasked
+pub fn unmask_payload(data_frame_info: &mut DataFrameInfo) {
+    if data_frame_info.contain_masked_data {
+        mask_unmask_data(&mut data_frame_info.payload_data, &data_frame_info.mask_key);
+        data_frame_info.contain_masked_data = false;
+        data_frame_info.mask_key = [0;4];
+    }
 }
 
-pub fn create_text_frame_with_data_length(data_len: usize) -> Vec<u8> {
-    let mut text_frame = Buffer::new_unbound();
-    let text_opcode = 0b10000001;
-    text_frame.append_byte(text_opcode);
-    let mut masked_bit: u8 = 0;
-
-    text_frame.append_vec8_array(
-        &get_payload_length_bits(data_len,masked_bit)
-    );
+//  If there is a body, the first two bytes of
+//    the body MUST be a 2-byte unsigned integer (in network byte order)
+//    representing a status code with value /code/ defined in Section 7.4.
+//    Following the 2-byte integer, the body MAY contain UTF-8-encoded data
+//    with value /reason/
+pub fn parse_close_payload(data_frame_info: &mut DataFrameInfo) -> Result<()> {
+    let payload = &data_frame_info.payload_data;
+    if payload.is_empty() {
+        return Ok(());
+    }
+    if payload.len() == 1 {
+        return Err(DataFrameError::new(ErrorKind::ProtocolError, "close frame with one byte payload"));
+    }
+    let close_code = u16::from_be_bytes([payload[0], payload[1]]);
+    if !is_valid_close_code(close_code) {
+        return Err(DataFrameError::new(ErrorKind::ProtocolError, "invalid close code"));
+    }
+    let close_reason = match std::str::from_utf8(&payload[2..]) {
+        Ok(close_reason) => close_reason.to_string(),
+        Err(_) => return Err(DataFrameError::new(ErrorKind::InvalidPayload, "close reason is not valid utf-8"))
+    };
+    data_frame_info.close_code = Some(close_code);
+    data_frame_info.close_reason = close_reason;
+    Ok(())
+}
 
-    text_frame.get_arr()
+// 1005, 1006 and 1015 are only for reporting and MUST NOT be sent in a Close frame,
+// 3000-3999 are registered with IANA and 4000-4999 are for private use
+fn is_valid_close_code(close_code: u16) -> bool {
+    matches!(close_code, 1000..=1003 | 1007..=1014 | 3000..=4999)
 }
 
-pub fn create_binary_frame_with_data_length(data_len: usize) -> Vec<u8> {
-    let mut text_frame = Buffer::new_unbound();
-    let binary_opcode = 0b10000010;
-    text_frame.append_byte(binary_opcode);
-    let mut masked_bit: u8 = 0;
+//  Close frames sent from client to server must be masked as per
+//    Section 5.3.  The application MUST NOT send any more data frames after sending
+//    a Close frame.
+pub fn create_close_dataframe(close_code: Option<u16>, close_reason: &str) -> DataFrameInfo {
+    let mut payload: Vec<u8> = Vec::new();
+    if let Some(close_code) = close_code {
+        payload.extend_from_slice(&close_code.to_be_bytes());
+        // control frame payload can't be more than 125 bytes
+        let mut reason_length = close_reason.len().min(123);
+        while !close_reason.is_char_boundary(reason_length) {
+            reason_length -= 1;
+        }
+        payload.extend_from_slice(&close_reason.as_bytes()[..reason_length]);
+    }
+    new_dataframe(Opcode::ConnectionClose, payload)
+}
 
-    text_frame.append_vec8_array(
-        &get_payload_length_bits(data_len,masked_bit)
-    );
+// a single unmasked frame carrying the complete payload
+pub fn new_dataframe(opcode: Opcode, payload_data: Vec<u8>) -> DataFrameInfo {
+    let mut data_frame_info = get_default_dataframe();
+    data_frame_info.opcode = opcode;
+    data_frame_info.is_this_final_frame = true;
+    data_frame_info.payload_length = payload_data.len();
+    data_frame_info.payload_data = payload_data;
+    data_frame_info
+}
 
-    text_frame.get_arr()
+fn get_opcode_bits(opcode: Opcode) -> u8 {
+    for opcode_mapping in OPCODES_ARRAY.iter() {
+        if opcode_mapping.1 == opcode {
+            return opcode_mapping.0;
+        }
+    }
+    0
 }
 
 fn process_data_frame_first_byte(data_frame_info: &mut DataFrameInfo, first_byte: u8) {
@@ -341,28 +335,27 @@ fn process_data_frame_second_byte(data_frame_info: &mut DataFrameInfo, second_by
     data_frame_info.payload_length_field = INITIAL_PAYLOAD_LENGTH_MASK & second_byte
 }
 
+// the 64 bit length can be anything the peer wants, it is only trusted after
+// validate_frame_header has checked its top bit and compared it against the
+// frame size limit
 fn calculate_payload_length(data_frame_info: &mut DataFrameInfo) {
-    let mut payload_length: usize = 0;
+    let mut payload_length: u64 = 0;
     if data_frame_info.payload_length_field <= 125 {
-        payload_length = data_frame_info.payload_length_field as usize;
+        payload_length = data_frame_info.payload_length_field as u64;
     }
 
     if data_frame_info.payload_length_field == 126 {
-        payload_length |= (data_frame_info.raw_bytes[2] as usize) << 8;
-        payload_length |=  data_frame_info.raw_bytes[3] as usize;
+        payload_length |= (data_frame_info.raw_bytes[2] as u64) << 8;
+        payload_length |=  data_frame_info.raw_bytes[3] as u64;
     }
     if data_frame_info.payload_length_field == 127 {
-        payload_length |= (data_frame_info.raw_bytes[2] as usize) << 56;
-        payload_length |= (data_frame_info.raw_bytes[3] as usize) << 48;
-        payload_length |= (data_frame_info.raw_bytes[4] as usize) << 40;
-        payload_length |= (data_frame_info.raw_bytes[5] as usize) << 32;
-        payload_length |= (data_frame_info.raw_bytes[6] as usize) << 24;
-        payload_length |= (data_frame_info.raw_bytes[7] as usize) << 16;
-        payload_length |= (data_frame_info.raw_bytes[8] as usize) << 8;
-        payload_length |= data_frame_info.raw_bytes[9] as usize
+        let mut length_bytes: [u8;8] = [0;8];
+        length_bytes.copy_from_slice(&data_frame_info.raw_bytes[2..10]);
+        payload_length = u64::from_be_bytes(length_bytes);
     }
 
-    data_frame_info.payload_length = payload_length;
+    // on 32 bit targets a length which does not fit is bigger than any limit anyway
+    data_frame_info.payload_length = usize::try_from(payload_length).unwrap_or(usize::MAX);
 }
 
 fn extract_mask_key(data_frame_info: &mut DataFrameInfo) {
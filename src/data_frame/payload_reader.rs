@@ -0,0 +1,190 @@
+// Read path for frames too big to be buffered. The decoder returns such a frame
+// with only its header, the payload is handed out as an AsyncRead which unmasks
+// the bytes as they arrive, so it can be copied to a file or another connection
+// chunk by chunk.
+//
+// Bytes already buffered by the FramedRead are used first, after the payload is
+// read to the end the FramedRead can be used again for the next frame.
+
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, ReadBuf};
+use tokio_util::codec::FramedRead;
+use super::{DataFrameInfo, FIN_BITMASK, Opcode, get_opcode_bits, get_payload_length_bits, mask_unmask_data};
+use super::codec::FrameDecoder;
+
+// payload of a single frame, reads return 0 once the whole payload was read
+pub struct PayloadReader<'a, R> {
+    frame_reader: &'a mut FramedRead<R, FrameDecoder>,
+    mask_key: Option<[u8;4]>,
+    bytes_left: usize,
+    // position in the payload, decides which byte of the mask key comes next
+    payload_offset: usize
+}
+
+impl<'a, R: AsyncRead + Unpin> PayloadReader<'a, R> {
+    // data_frame_info is the header the decoder returned for the frame
+    pub fn new(frame_reader: &'a mut FramedRead<R, FrameDecoder>, data_frame_info: &DataFrameInfo) -> PayloadReader<'a, R> {
+        PayloadReader {
+            frame_reader,
+            mask_key: if data_frame_info.contain_masked_data { Some(data_frame_info.mask_key) } else { None },
+            bytes_left: data_frame_info.payload_length,
+            payload_offset: 0
+        }
+    }
+
+    pub fn bytes_left(&self) -> usize {
+        self.bytes_left
+    }
+
+    fn unmask(&mut self, data: &mut [u8]) {
+        if let Some(mask_key) = self.mask_key {
+            let mut mask_key = mask_key;
+            mask_key.rotate_left(self.payload_offset % 4);
+            mask_unmask_data(data, &mask_key);
+        }
+        self.payload_offset += data.len();
+        self.bytes_left -= data.len();
+    }
+}
+
+impl<'a, R: AsyncRead + Unpin> AsyncRead for PayloadReader<'a, R> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let bytes_to_read = this.bytes_left.min(buf.remaining());
+        if bytes_to_read == 0 {
+            return Poll::Ready(Ok(()));
+        }
+
+        let read_buffer = this.frame_reader.read_buffer_mut();
+        if !read_buffer.is_empty() {
+            let mut data = read_buffer.split_to(bytes_to_read.min(read_buffer.len()));
+            this.unmask(&mut data);
+            buf.put_slice(&data);
+            return Poll::Ready(Ok(()));
+        }
+
+        // never read past the payload, the bytes after it belong to the next frame
+        let unfilled = buf.initialize_unfilled_to(bytes_to_read);
+        let mut limited_buf = ReadBuf::new(unfilled);
+        match Pin::new(this.frame_reader.get_mut()).poll_read(cx, &mut limited_buf) {
+            Poll::Ready(Ok(())) => {}
+            other => return other
+        }
+        let bytes_read = limited_buf.filled().len();
+        if bytes_read == 0 {
+            return Poll::Ready(Err(io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed in the middle of a frame")));
+        }
+        this.unmask(limited_buf.filled_mut());
+        buf.advance(bytes_read);
+        Poll::Ready(Ok(()))
+    }
+}
+
+// header of a final unmasked frame, the payload is written after it as it comes
+pub fn unmasked_frame_header(opcode: Opcode, payload_length: usize) -> Vec<u8> {
+    let mut header = vec![FIN_BITMASK | get_opcode_bits(opcode)];
+    header.extend_from_slice(&get_payload_length_bits(payload_length, 0));
+    header
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::BytesMut;
+    use futures_util::StreamExt;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream};
+    use tokio_util::codec::Encoder;
+    use crate::data_frame::{FrameValidationRules, Masking, ReadFrom, new_dataframe};
+    use crate::data_frame::codec::FrameEncoder;
+    use crate::service_config::StreamingConfig;
+    use super::*;
+
+    fn streaming_frame_reader(server: DuplexStream) -> FramedRead<DuplexStream, FrameDecoder> {
+        let validation_rules = FrameValidationRules {
+            masking: Masking::Required,
+            rsv1_allowed: false,
+            max_frame_size: 64
+        };
+        let streaming = StreamingConfig { min_frame_size: 32, max_frame_size: 1024 * 1024 };
+        FramedRead::new(server, FrameDecoder::new(validation_rules, ReadFrom::Socket).with_streaming(streaming))
+    }
+
+    fn masked_frame(opcode: Opcode, payload: &[u8]) -> Vec<u8> {
+        let mut frame_bytes = BytesMut::new();
+        FrameEncoder::new_client().encode(new_dataframe(opcode, payload.to_vec()), &mut frame_bytes).unwrap();
+        frame_bytes.to_vec()
+    }
+
+    fn test_payload(length: usize) -> Vec<u8> {
+        (0..length).map(|i| (i * 7 % 256) as u8).collect()
+    }
+
+    #[tokio::test]
+    async fn unmasks_at_odd_chunk_offsets() {
+        // a small pipe makes the header and the payload arrive in pieces
+        let (mut client, server) = tokio::io::duplex(5);
+        let mut frame_reader = streaming_frame_reader(server);
+        let payload = test_payload(1000);
+        let frame_bytes = masked_frame(Opcode::BinaryFrame, &payload);
+        let write = async move {
+            for chunk in frame_bytes.chunks(7) {
+                client.write_all(chunk).await.unwrap();
+            }
+            client
+        };
+        let read = async {
+            let data_frame = frame_reader.next().await.unwrap().unwrap();
+            assert!(data_frame.payload_streamed);
+            assert_eq!(data_frame.payload_length, payload.len());
+            let mut payload_reader = PayloadReader::new(&mut frame_reader, &data_frame);
+            let mut received = Vec::new();
+            let mut chunk = [0u8; 3];
+            while payload_reader.bytes_left() > 0 {
+                let bytes_read = payload_reader.read(&mut chunk).await.unwrap();
+                received.extend_from_slice(&chunk[..bytes_read]);
+            }
+            received
+        };
+        let (_client, received) = tokio::join!(write, read);
+        assert_eq!(received, payload);
+    }
+
+    #[tokio::test]
+    async fn buffered_payload_is_read_first() {
+        let (mut client, server) = tokio::io::duplex(8192);
+        let mut frame_reader = streaming_frame_reader(server);
+        let payload = test_payload(500);
+        let mut frame_bytes = masked_frame(Opcode::BinaryFrame, &payload);
+        // a frame under min_frame_size right after, decoded the usual way
+        frame_bytes.extend_from_slice(&masked_frame(Opcode::TextFrame, b"next"));
+        client.write_all(&frame_bytes).await.unwrap();
+
+        let data_frame = frame_reader.next().await.unwrap().unwrap();
+        assert!(data_frame.payload_streamed);
+        assert!(!frame_reader.read_buffer().is_empty());
+        let mut received = Vec::new();
+        PayloadReader::new(&mut frame_reader, &data_frame).read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, payload);
+
+        let mut next_frame = frame_reader.next().await.unwrap().unwrap();
+        assert!(!next_frame.payload_streamed);
+        crate::data_frame::unmask_payload(&mut next_frame);
+        assert_eq!(next_frame.payload_data, b"next");
+    }
+
+    #[tokio::test]
+    async fn connection_closed_in_the_middle_of_the_payload() {
+        let (mut client, server) = tokio::io::duplex(8192);
+        let mut frame_reader = streaming_frame_reader(server);
+        let frame_bytes = masked_frame(Opcode::BinaryFrame, &test_payload(500));
+        client.write_all(&frame_bytes[..frame_bytes.len() - 100]).await.unwrap();
+        drop(client);
+
+        let data_frame = frame_reader.next().await.unwrap().unwrap();
+        let mut received = Vec::new();
+        let e = PayloadReader::new(&mut frame_reader, &data_frame).read_to_end(&mut received).await.unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof);
+        assert_eq!(received.len(), 400);
+    }
+}
@@ -0,0 +1,43 @@
+use std::fmt;
+
+pub type Result<T> = std::result::Result<T, ClientError>;
+
+// reason pollux could not open a websocket to a server
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ErrorKind {
+    // request could not be built from the address, path and headers given
+    InvalidRequest,
+    // not able to connect, send the request or read the response
+    ConnectionFailed,
+    // server did not answer before the handshake deadline
+    Timeout,
+    // response is not HTTP or is too big
+    InvalidResponse,
+    // response does not open the websocket that was asked for
+    HandshakeRejected
+}
+
+#[derive(Debug)]
+pub struct ClientError {
+    message: String,
+    error_kind: ErrorKind
+}
+
+impl ClientError {
+    pub fn new(error_kind: ErrorKind, message: &str) -> ClientError {
+        ClientError {
+            message: message.to_string(),
+            error_kind
+        }
+    }
+
+    pub fn error_kind(&self) -> ErrorKind {
+        self.error_kind
+    }
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f,"Client Error occurred, ErrorKind: {:?}, message: {}",self.error_kind,self.message)
+    }
+}
@@ -1,21 +1,79 @@
 use std::fmt;
+use crate::data_frame::CloseCode;
 
-type Result<T> = std::result::Result<T, DataFrameError>;
+pub type Result<T> = std::result::Result<T, DataFrameError>;
 
 #[derive(Debug)]
-enum ErrorKind {
+pub enum ErrorKind {
     InvalidOpcode,
+    ControlFrameTooBig,
+    FragmentedControlFrame,
+    UnmaskedFrame,
+    MaskedFrame,
+    ReservedBitsSet,
+    UnexpectedContinuationFrame,
+    ExpectedContinuationFrame,
+    MessageTooBig,
+    ProtocolError,
+    InvalidPayload,
+    UnsupportedData,
+    ConnectionDropped,
+    PongTimeout,
+    FrameTimeout,
+    IdleTimeout,
     UnknownError
 }
 
 #[derive(Debug)]
-struct DataFrameError {
+pub struct DataFrameError {
     message: String,
     error_kind: ErrorKind
 }
 
+impl DataFrameError {
+    pub fn new(error_kind: ErrorKind, message: &str) -> DataFrameError {
+        DataFrameError {
+            message: message.to_string(),
+            error_kind
+        }
+    }
+
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+
+    // status code to send in the Close frame, None when the connection is already gone
+    pub fn close_code(&self) -> Option<CloseCode> {
+        match self.error_kind {
+            ErrorKind::InvalidOpcode |
+            ErrorKind::ControlFrameTooBig |
+            ErrorKind::FragmentedControlFrame |
+            ErrorKind::UnmaskedFrame |
+            ErrorKind::MaskedFrame |
+            ErrorKind::ReservedBitsSet |
+            ErrorKind::UnexpectedContinuationFrame |
+            ErrorKind::ExpectedContinuationFrame |
+            ErrorKind::ProtocolError => Some(CloseCode::ProtocolError),
+            ErrorKind::MessageTooBig => Some(CloseCode::MessageTooBig),
+            ErrorKind::InvalidPayload => Some(CloseCode::InvalidPayload),
+            ErrorKind::UnsupportedData => Some(CloseCode::UnsupportedData),
+            ErrorKind::PongTimeout | ErrorKind::IdleTimeout => Some(CloseCode::GoingAway),
+            ErrorKind::FrameTimeout => Some(CloseCode::PolicyViolation),
+            ErrorKind::ConnectionDropped => None,
+            ErrorKind::UnknownError => Some(CloseCode::InternalError)
+        }
+    }
+}
+
 impl fmt::Display for DataFrameError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f,"Data Frame Error occurred, ErrorKind: {:?}, message: {}",self.error_kind,self.message)
     }
 }
+
+// io errors only come from the connection underneath the frames
+impl From<std::io::Error> for DataFrameError {
+    fn from(e: std::io::Error) -> Self {
+        DataFrameError::new(ErrorKind::ConnectionDropped, &e.to_string())
+    }
+}
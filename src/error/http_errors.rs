@@ -1,22 +1,71 @@
 use std::fmt;
-use crate::data_frame::Opcode;
+use http::StatusCode;
 
-type Result<T> = std::result::Result<T, HTTPError>;
+pub type Result<T> = std::result::Result<T, HTTPError>;
 
+// reason a handshake was refused, decides the status of the response
 #[derive(Debug)]
-enum ErrorKind {
-    InvalidOpcode(Opcode),
-    UnknownError(String)
+pub enum ErrorKind {
+    // malformed handshake, e.g. an invalid Sec-WebSocket-Key
+    BadRequest,
+    Unauthorized,
+    Forbidden,
+    // no route for the path
+    NotFound,
+    // method other than GET
+    MethodNotAllowed,
+    // not a websocket upgrade or a version other than 13
+    UpgradeRequired,
+    // request did not arrive before the handshake deadline
+    RequestTimeout,
+    // request line and headers bigger than allowed
+    RequestHeaderFieldsTooLarge,
+    // connection limits reached
+    ServiceUnavailable,
+    // connection limits reached, closed without a response
+    ConnectionRefused
 }
 
 #[derive(Debug)]
-struct HTTPError {
+pub struct HTTPError {
     message: String,
     error_kind: ErrorKind
 }
 
+impl HTTPError {
+    pub fn new(error_kind: ErrorKind, message: &str) -> HTTPError {
+        HTTPError {
+            message: message.to_string(),
+            error_kind
+        }
+    }
+
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+
+    pub fn status_code(&self) -> StatusCode {
+        match self.error_kind {
+            ErrorKind::BadRequest => StatusCode::BAD_REQUEST,
+            ErrorKind::Unauthorized => StatusCode::UNAUTHORIZED,
+            ErrorKind::Forbidden => StatusCode::FORBIDDEN,
+            ErrorKind::NotFound => StatusCode::NOT_FOUND,
+            ErrorKind::MethodNotAllowed => StatusCode::METHOD_NOT_ALLOWED,
+            ErrorKind::UpgradeRequired => StatusCode::UPGRADE_REQUIRED,
+            ErrorKind::RequestTimeout => StatusCode::REQUEST_TIMEOUT,
+            ErrorKind::RequestHeaderFieldsTooLarge => StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE,
+            ErrorKind::ServiceUnavailable | ErrorKind::ConnectionRefused => StatusCode::SERVICE_UNAVAILABLE
+        }
+    }
+
+    // false when the connection is to be closed without an error response
+    pub fn has_response(&self) -> bool {
+        !matches!(self.error_kind, ErrorKind::ConnectionRefused)
+    }
+}
+
 impl fmt::Display for HTTPError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f,"Data Frame Error occurred, message ErrorKind: {:?}, message: {}",self.error_kind,self.message)
+        write!(f,"HTTP Error occurred, ErrorKind: {:?}, message: {}",self.error_kind,self.message)
     }
 }
@@ -1,4 +1,6 @@
-mod http_errors;
-mod data_frame_error;
+pub mod http_errors;
+pub mod data_frame_error;
 mod tcp_errors;
 pub mod pollux_error;
+#[cfg(test)]
+pub mod client_errors;
@@ -0,0 +1,108 @@
+//  A fragmented message consists of a single frame with the FIN bit
+//    clear and an opcode other than 0, followed by zero or more frames
+//    with the FIN bit clear and the opcode set to 0, and terminated by
+//    a single frame with the FIN bit set and an opcode of 0.
+//
+//  Control frames MAY be injected in the middle of
+//    a fragmented message.  Control frames themselves MUST NOT be
+//    fragmented.
+
+use crate::data_frame::{self, DataFrameInfo, Opcode};
+use crate::error::data_frame_error::{DataFrameError, ErrorKind, Result};
+use crate::utf8_validator::Utf8Validator;
+
+// keeps the fragments of the message currently being received on a connection
+pub struct FragmentHandler {
+    message_in_progress: Option<DataFrameInfo>,
+    utf8_validator: Utf8Validator,
+    // upper bound for a message built out of fragments, a client should not be able
+    // to grow the buffer forever by never sending the final frame
+    max_message_size: usize
+}
+
+impl FragmentHandler {
+    pub fn new(max_message_size: usize) -> FragmentHandler {
+        FragmentHandler {
+            message_in_progress: None,
+            utf8_validator: Utf8Validator::new(),
+            max_message_size
+        }
+    }
+
+    pub fn is_message_in_progress(&self) -> bool {
+        self.message_in_progress.is_some()
+    }
+
+    // frames have to be passed in the order they were read from the socket,
+    // returns the complete message once its final frame has arrived. Control
+    // frames are returned as they are
+    pub fn process_frame(&mut self, mut data_frame: DataFrameInfo) -> Result<Option<DataFrameInfo>> {
+        data_frame::unmask_payload(&mut data_frame);
+
+        if data_frame.opcode.is_control_frame() {
+            return Ok(Some(data_frame));
+        }
+
+        if data_frame.opcode == Opcode::ContinuationFrame {
+            let mut message = match self.message_in_progress.take() {
+                None => {
+                    crate::error!("Continuation frame received without a message in progress");
+                    return Err(DataFrameError::new(
+                        ErrorKind::UnexpectedContinuationFrame,
+                        "continuation frame received without a message in progress"
+                    ));
+                }
+                Some(message) => message
+            };
+            self.check_message_size(message.payload_data.len() + data_frame.payload_data.len())?;
+            if needs_utf8_validation(&message) {
+                self.utf8_validator.validate_fragment(&data_frame.payload_data, data_frame.is_this_final_frame)?;
+            }
+            message.payload_data.append(&mut data_frame.payload_data);
+            message.payload_length = message.payload_data.len();
+
+            if !data_frame.is_this_final_frame {
+                self.message_in_progress = Some(message);
+                return Ok(None);
+            }
+            crate::info!("Fragmented message complete, size: {}",message.payload_length);
+            message.is_this_final_frame = true;
+            return Ok(Some(message));
+        }
+
+        if self.message_in_progress.is_some() {
+            crate::error!("New message started before the fragmented message was complete");
+            return Err(DataFrameError::new(
+                ErrorKind::ExpectedContinuationFrame,
+                "new message started before the fragmented message was complete"
+            ));
+        }
+
+        self.check_message_size(data_frame.payload_data.len())?;
+        if needs_utf8_validation(&data_frame) {
+            self.utf8_validator = Utf8Validator::new();
+            self.utf8_validator.validate_fragment(&data_frame.payload_data, data_frame.is_this_final_frame)?;
+        }
+
+        if data_frame.is_this_final_frame {
+            return Ok(Some(data_frame));
+        }
+
+        crate::info!("Fragmented message started, opcode: {:?}",data_frame.opcode);
+        self.message_in_progress = Some(data_frame);
+        Ok(None)
+    }
+
+    fn check_message_size(&self, message_size: usize) -> Result<()> {
+        if message_size > self.max_message_size {
+            crate::error!("Message size: {} is more than the limit: {}",message_size,self.max_message_size);
+            return Err(DataFrameError::new(ErrorKind::MessageTooBig, "message is more than the size limit"));
+        }
+        Ok(())
+    }
+}
+
+// compressed text is validated once the message is inflated
+fn needs_utf8_validation(data_frame: &DataFrameInfo) -> bool {
+    data_frame.opcode == Opcode::TextFrame && !data_frame.rs1_bit_set
+}
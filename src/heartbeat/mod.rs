@@ -0,0 +1,81 @@
+//  A Ping frame MAY be sent after the connection is
+//    established and before the connection is closed.
+//
+//  A Pong frame sent in response to a Ping frame must have identical
+//    "Application data" as found in the message body of the Ping frame
+//    being replied to.
+//
+// Server sends a Ping every ping interval, a peer which does not reply in time
+// is treated as gone. Connections behind NAT can die without any FIN reaching us.
+
+use tokio::time::{Duration, Instant};
+use crate::service_config::HeartbeatConfig;
+
+pub enum HeartbeatAction {
+    SendPing(Vec<u8>),
+    PongTimedOut
+}
+
+// heartbeat state of one connection
+pub struct Heartbeat {
+    ping_interval: Duration,
+    pong_timeout: Duration,
+    pings_sent: u64,
+    last_ping_sent_at: Instant,
+    // payload of the ping we are waiting a pong for
+    pong_expected: Option<Vec<u8>>,
+    pub last_round_trip_time: Option<Duration>
+}
+
+impl Heartbeat {
+    pub fn new(heartbeat_config: &HeartbeatConfig) -> Heartbeat {
+        Heartbeat {
+            ping_interval: Duration::from_secs(heartbeat_config.ping_interval_secs),
+            pong_timeout: Duration::from_secs(heartbeat_config.pong_timeout_secs),
+            pings_sent: 0,
+            last_ping_sent_at: Instant::now(),
+            pong_expected: None,
+            last_round_trip_time: None
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.ping_interval.is_zero()
+    }
+
+    // when the connection has to be looked at again, either to send the next ping
+    // or to give up on the one sent
+    pub fn next_deadline(&self) -> Instant {
+        match self.pong_expected {
+            Some(_) => self.last_ping_sent_at + self.pong_timeout,
+            None => self.last_ping_sent_at + self.ping_interval
+        }
+    }
+
+    pub fn on_deadline(&mut self) -> HeartbeatAction {
+        if self.pong_expected.is_some() {
+            crate::error!("No Pong received in {:?}",self.pong_timeout);
+            return HeartbeatAction::PongTimedOut;
+        }
+        self.pings_sent += 1;
+        let ping_payload = self.pings_sent.to_be_bytes().to_vec();
+        self.pong_expected = Some(ping_payload.clone());
+        self.last_ping_sent_at = Instant::now();
+        HeartbeatAction::SendPing(ping_payload)
+    }
+
+    //  A Pong frame MAY be sent unsolicited.  This serves as a
+    //    unidirectional heartbeat.  A response to an unsolicited Pong frame is
+    //    not expected.
+    pub fn pong_received(&mut self, pong_payload: &[u8]) {
+        match &self.pong_expected {
+            Some(ping_payload) if ping_payload.as_slice() == pong_payload => {
+                let round_trip_time = self.last_ping_sent_at.elapsed();
+                crate::info!("Pong received, round trip time: {:?}",round_trip_time);
+                self.last_round_trip_time = Some(round_trip_time);
+                self.pong_expected = None;
+            }
+            _ => crate::info!("Unsolicited Pong received")
+        }
+    }
+}
@@ -0,0 +1,191 @@
+// Client side of the opening handshake, used when pollux itself connects to a
+// websocket server.
+//
+//         GET /chat HTTP/1.1
+//         Host: server.example.com
+//         Upgrade: websocket
+//         Connection: Upgrade
+//         Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==
+//         Sec-WebSocket-Protocol: chat, superchat
+//         Sec-WebSocket-Version: 13
+
+use http::{Request, Response, StatusCode, Version, HeaderMap};
+use http::header::{
+    HeaderName,
+    HeaderValue,
+    HOST,
+    UPGRADE,
+    CONNECTION,
+    SEC_WEBSOCKET_KEY,
+    SEC_WEBSOCKET_ACCEPT,
+    SEC_WEBSOCKET_PROTOCOL,
+    SEC_WEBSOCKET_VERSION,
+    SEC_WEBSOCKET_EXTENSIONS
+};
+use httparse::{EMPTY_HEADER, Status};
+use crate::error::client_errors::{self, ClientError, ErrorKind};
+use super::WEBSOCKET_VERSION_SUPPORTED;
+
+// what the response has to be checked against
+pub struct ClientHandshake {
+    pub request: Request<()>,
+    sec_ws_key: String,
+    sub_protocols_offered: Vec<String>
+}
+
+//  The request MUST include a header field with the name
+//    |Sec-WebSocket-Key|.  The value of this header field MUST be a
+//    nonce consisting of a randomly selected 16-byte value that has
+//    been base64-encoded.  The nonce MUST be selected randomly for each
+//    connection.
+pub fn create_client_handshake_request(host: &str, path: &str, sub_protocols: &[&str], extra_headers: &[(HeaderName, String)]) -> client_errors::Result<ClientHandshake> {
+    let sec_ws_key = base64::encode(rand::random::<[u8;16]>());
+    let mut request_builder = Request::builder()
+        .method("GET")
+        .uri(path)
+        .version(Version::HTTP_11)
+        .header(HOST, host)
+        .header(UPGRADE, HeaderValue::from_static("websocket"))
+        .header(CONNECTION, HeaderValue::from_static("Upgrade"))
+        .header(SEC_WEBSOCKET_KEY, sec_ws_key.as_str())
+        .header(SEC_WEBSOCKET_VERSION, WEBSOCKET_VERSION_SUPPORTED);
+    if !sub_protocols.is_empty() {
+        request_builder = request_builder.header(SEC_WEBSOCKET_PROTOCOL, sub_protocols.join(", "));
+    }
+    for (header_name, header_value) in extra_headers {
+        request_builder = request_builder.header(header_name, header_value.as_str());
+    }
+    let request = match request_builder.body(()) {
+        Ok(request) => request,
+        Err(e) => {
+            crate::error!("Not able to create handshake request: {}",e);
+            return Err(ClientError::new(ErrorKind::InvalidRequest, "not able to create handshake request"));
+        }
+    };
+    Ok(ClientHandshake {
+        request,
+        sec_ws_key,
+        sub_protocols_offered: sub_protocols.iter().map(|sub_protocol| sub_protocol.to_string()).collect()
+    })
+}
+
+pub fn get_http_request_bytes(request: &Request<()>) -> Vec<u8> {
+    let mut bytes: Vec<u8> = Vec::new();
+    bytes.extend_from_slice(format!("{} {} HTTP/1.1\r\n", request.method(), request.uri()).as_bytes());
+    for (header_name, header_value) in request.headers() {
+        bytes.extend_from_slice(header_name.as_str().as_bytes());
+        bytes.extend_from_slice(b": ");
+        bytes.extend_from_slice(header_value.as_bytes());
+        bytes.extend_from_slice(b"\r\n");
+    }
+    bytes.extend_from_slice(b"\r\n");
+    bytes
+}
+
+// returns the response and the length of its head, None if the head is not complete yet.
+// Bytes after the head are already websocket frames
+pub fn parse_http_response_bytes(bytes: &[u8]) -> client_errors::Result<Option<(Response<()>, usize)>> {
+    let mut headers = [EMPTY_HEADER;30];
+    let mut http_parser_resp = httparse::Response::new(&mut headers);
+    let head_length = match http_parser_resp.parse(bytes) {
+        Ok(Status::Complete(head_length)) => head_length,
+        Ok(Status::Partial) => return Ok(None),
+        Err(e) => {
+            crate::error!("Error while parsing http response: {} ",e);
+            return Err(ClientError::new(ErrorKind::InvalidResponse, "not able to parse handshake response"));
+        }
+    };
+
+    let status_code = match http_parser_resp.code.map(StatusCode::from_u16) {
+        Some(Ok(status_code)) => status_code,
+        _ => return Err(ClientError::new(ErrorKind::InvalidResponse, "status code missing from handshake response"))
+    };
+    let mut response_builder = Response::builder()
+        .status(status_code)
+        .version(Version::HTTP_11);
+    for header in http_parser_resp.headers.iter() {
+        let header_name = match HeaderName::from_bytes(header.name.as_bytes()) {
+            Ok(header_name) => header_name,
+            Err(_) => return Err(ClientError::new(ErrorKind::InvalidResponse, "invalid header name in handshake response"))
+        };
+        let header_value = match HeaderValue::from_bytes(header.value) {
+            Ok(header_value) => header_value,
+            Err(_) => return Err(ClientError::new(ErrorKind::InvalidResponse, "invalid header value in handshake response"))
+        };
+        response_builder = response_builder.header(header_name, header_value);
+    }
+    match response_builder.body(()) {
+        Ok(response) => Ok(Some((response, head_length))),
+        Err(_) => Err(ClientError::new(ErrorKind::InvalidResponse, "not able to build handshake response"))
+    }
+}
+
+//  If the status code received from the server is not 101, the
+//    client handles the response per HTTP [RFC2616] procedures.
+//
+//  If the response lacks an |Upgrade| header field or the |Upgrade|
+//    header field contains a value that is not an ASCII case-
+//    insensitive match for the value "websocket", the client MUST
+//    _Fail the WebSocket Connection_.
+//
+// returns the subprotocol selected by the server
+pub fn validate_handshake_response(response: &Response<()>, client_handshake: &ClientHandshake) -> client_errors::Result<Option<String>> {
+    if response.status() != StatusCode::SWITCHING_PROTOCOLS {
+        crate::error!("Server replied to handshake with status: {}",response.status());
+        return Err(ClientError::new(ErrorKind::HandshakeRejected, "server did not switch protocols"));
+    }
+    let header_map = response.headers();
+    if !header_value_is(header_map, &UPGRADE, "websocket") {
+        return Err(ClientError::new(ErrorKind::HandshakeRejected, "upgrade header is not websocket"));
+    }
+    if !header_value_is(header_map, &CONNECTION, "upgrade") {
+        return Err(ClientError::new(ErrorKind::HandshakeRejected, "connection header is not upgrade"));
+    }
+
+    //  If the response lacks a |Sec-WebSocket-Accept| header field or
+    //    the |Sec-WebSocket-Accept| contains a value other than the
+    //    base64-encoded SHA-1 of the concatenation of the |Sec-WebSocket-
+    //    Key| (as a string, not base64-decoded) with the string "258EAFA5-
+    //    E914-47DA-95CA-C5AB0DC85B11" but ignoring any leading and
+    //    trailing whitespace, the client MUST _Fail the WebSocket
+    //    Connection_.
+    let accept_key_expected = super::create_accept_key(&client_handshake.sec_ws_key);
+    match header_map.get(SEC_WEBSOCKET_ACCEPT).map(|header_value| header_value.to_str()) {
+        Some(Ok(accept_key)) if accept_key.trim() == accept_key_expected => {}
+        _ => {
+            crate::error!("Sec-WebSocket-Accept does not match the key sent");
+            return Err(ClientError::new(ErrorKind::HandshakeRejected, "Sec-WebSocket-Accept does not match"));
+        }
+    }
+
+    //  If the response includes a |Sec-WebSocket-Extensions| header
+    //    field and this header field indicates the use of an extension
+    //    that was not present in the client's handshake, the client MUST
+    //    _Fail the WebSocket Connection_.
+    if header_map.contains_key(SEC_WEBSOCKET_EXTENSIONS) {
+        return Err(ClientError::new(ErrorKind::HandshakeRejected, "server selected an extension which was not offered"));
+    }
+
+    //  If the response includes a |Sec-WebSocket-Protocol| header field
+    //    and this header field indicates the use of a subprotocol that was
+    //    not present in the client's handshake, the client MUST _Fail
+    //    the WebSocket Connection_.
+    match header_map.get(SEC_WEBSOCKET_PROTOCOL).map(|header_value| header_value.to_str()) {
+        None => Ok(None),
+        Some(Ok(sub_protocol)) if client_handshake.sub_protocols_offered.iter().any(|offered| offered == sub_protocol.trim()) => {
+            Ok(Some(sub_protocol.trim().to_string()))
+        }
+        Some(_) => Err(ClientError::new(ErrorKind::HandshakeRejected, "server selected a subprotocol which was not offered"))
+    }
+}
+
+fn header_value_is(header_map: &HeaderMap, header_name: &HeaderName, header_value_expected: &str) -> bool {
+    let value_matched = match header_map.get(header_name).map(|header_value| header_value.to_str()) {
+        Some(Ok(header_value)) => header_value.eq_ignore_ascii_case(header_value_expected),
+        _ => false
+    };
+    if !value_matched {
+        crate::error!("Expected value: {} for header: {}",header_value_expected,header_name.as_str());
+    }
+    value_matched
+}
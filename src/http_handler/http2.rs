@@ -0,0 +1,337 @@
+//  On receipt of SETTINGS_ENABLE_CONNECT_PROTOCOL with a value of 1, a
+//    client MAY use the Extended CONNECT as defined in this document when
+//    creating new streams.
+//
+//  The :protocol pseudo-header field MUST be included in the CONNECT
+//    request, and it MUST have a value of "websocket" to initiate a
+//    WebSocket connection on an HTTP/2 stream.
+//
+// Every stream of the connection is a websocket of its own and is served like
+// one opened by an HTTP/1.1 upgrade, the stream carries the frames.
+
+use std::cmp::min;
+use std::io;
+use std::net::IpAddr;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::SystemTime;
+use bytes::{Buf, Bytes, BytesMut};
+use futures_util::ready;
+use h2::{Reason, RecvStream, SendStream};
+use h2::ext::Protocol;
+use h2::server::{self, SendResponse};
+use http::{Method, Request, Response, StatusCode};
+use http::header::{CONNECTION, DATE, HOST, HeaderValue, SERVER, UPGRADE};
+use httpdate::fmt_http_date;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time::Instant;
+use crate::connection_handler;
+use crate::error::http_errors::{self, ErrorKind, HTTPError};
+use crate::service_config::{Http2Config, ListenerConfig};
+use super::{HandshakeDetails, http_endpoints};
+
+pub async fn serve_http2_connection<T>(io: T, http2_config: &Http2Config, peer_ip: IpAddr, listener: &'static ListenerConfig, handshake_deadline: Instant, connection_admitted: bool)
+    where T: AsyncRead + AsyncWrite + Unpin {
+    let mut builder = server::Builder::new();
+    builder.enable_connect_protocol()
+        .max_concurrent_streams(http2_config.max_concurrent_streams);
+    // the client has to send its preface and settings by the handshake deadline
+    let mut connection = match tokio::time::timeout_at(handshake_deadline, builder.handshake::<_, Bytes>(io)).await {
+        Ok(Ok(connection)) => connection,
+        Ok(Err(e)) => {
+            crate::error!("HTTP/2 handshake failed: {}",e);
+            return;
+        }
+        Err(_) => {
+            crate::error!("HTTP/2 handshake not done in {}s",crate::SERVICE_CONFIG.handshake.timeout_secs);
+            return;
+        }
+    };
+    crate::info!("HTTP/2 connection established");
+    // streams make progress only while the connection is polled here
+    while let Some(result) = connection.accept().await {
+        match result {
+            Ok((request, respond)) => {
+                tokio::spawn(async move {
+                    serve_stream(request, respond, peer_ip, listener, connection_admitted).await;
+                });
+            }
+            Err(e) => {
+                crate::error!("HTTP/2 connection error: {}",e);
+                return;
+            }
+        }
+    }
+    crate::info!("HTTP/2 connection closed");
+}
+
+async fn serve_stream(request: Request<RecvStream>, mut respond: SendResponse<Bytes>, peer_ip: IpAddr, listener: &ListenerConfig, connection_admitted: bool) {
+    let (parts, recv_stream) = request.into_parts();
+    let request = Request::from_parts(parts, ());
+    // the query can carry a token, only the path is logged
+    crate::info!("HTTP/2 request: {} {}",request.method(),request.uri().path());
+
+    let (http_resp, handshake_details) = match handle_http2_request(&request, peer_ip, listener, connection_admitted).await {
+        Ok((http_resp, handshake_details)) => (http_resp, handshake_details),
+        // the stream is refused, other streams of the connection are kept
+        Err(e) if !e.has_response() => {
+            crate::error!("Refusing stream: {}",e);
+            respond.send_reset(Reason::REFUSED_STREAM);
+            return;
+        }
+        Err(e) => {
+            crate::error!("Handshake refused: {}",e);
+            (super::create_error_response(&e), None)
+        }
+    };
+    let (mut parts, body) = http_resp.into_parts();
+    //  An endpoint MUST NOT generate an HTTP/2 message containing
+    //    connection-specific header fields
+    parts.headers.remove(CONNECTION);
+    parts.headers.remove(UPGRADE);
+    let end_of_stream = body.is_empty() && handshake_details.is_none();
+    let mut send_stream = match respond.send_response(Response::from_parts(parts, ()), end_of_stream) {
+        Ok(send_stream) => send_stream,
+        Err(e) => {
+            crate::error!("Enable to send response : {}",e);
+            return;
+        }
+    };
+    match handshake_details {
+        Some(handshake_details) => {
+            let read_half = H2StreamReader::new(recv_stream);
+            let write_half = H2StreamWriter::new(send_stream);
+            connection_handler::serve_websocket_connection(read_half, write_half, handshake_details, BytesMut::new()).await;
+        }
+        None if !end_of_stream => {
+            if let Err(e) = send_stream.send_data(Bytes::from(body), true) {
+                crate::error!("Enable to send response body : {}",e);
+            }
+        }
+        None => {}
+    }
+}
+
+// a request for one of the HTTP endpoints gets a normal response, everything else
+// has to be an extended CONNECT opening a websocket
+async fn handle_http2_request(request: &Request<()>, peer_ip: IpAddr, listener: &ListenerConfig, connection_admitted: bool) -> http_errors::Result<(Response<String>,Option<HandshakeDetails>)> {
+    let protocol = request.extensions().get::<Protocol>().map(|protocol| protocol.as_str());
+    if request.method() != Method::CONNECT || protocol != Some("websocket") {
+        if let Some(endpoint) = http_endpoints::find_endpoint(request.uri().path()) {
+            crate::info!("Serving HTTP endpoint: {}",request.uri().path());
+            if request.method() != Method::GET {
+                return Err(HTTPError::new(ErrorKind::MethodNotAllowed, "only GET is allowed"));
+            }
+            return Ok((endpoint(request).await, None));
+        }
+        crate::error!("Not an extended CONNECT for websocket: {} {:?}",request.method(),protocol);
+        return Err(HTTPError::new(ErrorKind::BadRequest, "websocket needs an extended CONNECT"));
+    }
+
+    //  The :authority pseudo-header field contains the authority portion
+    //    of the target URI
+    let host = match request.uri().authority() {
+        Some(authority) => authority.as_str().to_string(),
+        None => match request.headers().get(HOST).map(|header_value| header_value.to_str()) {
+            Some(Ok(host)) => host.trim().to_string(),
+            _ => {
+                crate::error!("Authority missing or invalid");
+                return Err(HTTPError::new(ErrorKind::BadRequest, "invalid authority"));
+            }
+        }
+    };
+    let route_match = super::match_host_and_route(listener, &host, request.uri())?;
+    super::check_websocket_version(request.headers())?;
+
+    //  Sec-WebSocket-Key and Sec-WebSocket-Accept are not used, the
+    //    websocket is open once a 2xx response is received
+    let response_builder = Response::builder()
+        .status(StatusCode::OK)
+        .header(SERVER,HeaderValue::from_static(super::SERVER_NAME))
+        .header(DATE,fmt_http_date(SystemTime::now()));
+    let (response_builder, handshake_details) = super::accept_websocket(request, peer_ip, listener, connection_admitted, route_match, response_builder)?;
+    Ok((response_builder.body(String::new()).unwrap(), Some(handshake_details)))
+}
+
+fn to_io_error(e: h2::Error) -> io::Error {
+    io::Error::other(e)
+}
+
+// frames sent by the client arrive as DATA on the stream, the window is given
+// back as soon as the data is taken out of h2
+pub struct H2StreamReader {
+    recv_stream: RecvStream,
+    buffered: Bytes
+}
+
+impl H2StreamReader {
+    pub fn new(recv_stream: RecvStream) -> H2StreamReader {
+        H2StreamReader { recv_stream, buffered: Bytes::new() }
+    }
+}
+
+impl AsyncRead for H2StreamReader {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        while self.buffered.is_empty() {
+            match ready!(self.recv_stream.poll_data(cx)) {
+                Some(Ok(data)) => {
+                    let _ = self.recv_stream.flow_control().release_capacity(data.len());
+                    self.buffered = data;
+                }
+                Some(Err(e)) => return Poll::Ready(Err(to_io_error(e))),
+                // end of stream
+                None => return Poll::Ready(Ok(()))
+            }
+        }
+        let n = min(self.buffered.len(), buf.remaining());
+        buf.put_slice(&self.buffered[..n]);
+        self.buffered.advance(n);
+        Poll::Ready(Ok(()))
+    }
+}
+
+// writes only as much as the peer's window allows, the stream is ended when
+// the writer is shut down or dropped
+pub struct H2StreamWriter {
+    send_stream: SendStream<Bytes>,
+    ended: bool
+}
+
+impl H2StreamWriter {
+    pub fn new(send_stream: SendStream<Bytes>) -> H2StreamWriter {
+        H2StreamWriter { send_stream, ended: false }
+    }
+}
+
+impl AsyncWrite for H2StreamWriter {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        self.send_stream.reserve_capacity(buf.len());
+        loop {
+            let capacity = self.send_stream.capacity();
+            if capacity > 0 {
+                let n = min(capacity, buf.len());
+                self.send_stream.send_data(Bytes::copy_from_slice(&buf[..n]), false).map_err(to_io_error)?;
+                return Poll::Ready(Ok(n));
+            }
+            match ready!(self.send_stream.poll_capacity(cx)) {
+                Some(Ok(_)) => continue,
+                Some(Err(e)) => return Poll::Ready(Err(to_io_error(e))),
+                None => return Poll::Ready(Err(io::Error::new(io::ErrorKind::BrokenPipe, "stream closed")))
+            }
+        }
+    }
+
+    // data is flushed by the connection
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if !self.ended {
+            self.ended = true;
+            self.send_stream.send_data(Bytes::new(), true).map_err(to_io_error)?;
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Drop for H2StreamWriter {
+    fn drop(&mut self) {
+        if !self.ended {
+            let _ = self.send_stream.send_data(Bytes::new(), true);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::BytesMut;
+    use http::header::ALLOW;
+    use tokio::time::Duration;
+    use tokio_util::codec::Encoder;
+    use crate::data_frame::{Opcode, new_dataframe};
+    use crate::data_frame::codec::FrameEncoder;
+    use super::*;
+
+    fn test_listener() -> &'static ListenerConfig {
+        Box::leak(Box::new(ListenerConfig {
+            name: "http2-test".to_string(),
+            address: "127.0.0.1:0".to_string(),
+            dual_stack: false,
+            tls: false,
+            routes: None,
+            host_names: vec!["*".to_string()],
+            size_limits: None
+        }))
+    }
+
+    async fn connect() -> h2::client::SendRequest<Bytes> {
+        let (client_io, server_io) = tokio::io::duplex(16384);
+        let http2_config = Http2Config { enabled: true, max_concurrent_streams: 10 };
+        tokio::spawn(async move {
+            let handshake_deadline = crate::tcp_handler::handshake_deadline(&crate::SERVICE_CONFIG.handshake);
+            serve_http2_connection(server_io, &http2_config, IpAddr::from([127, 0, 0, 1]), test_listener(), handshake_deadline, true).await;
+        });
+
+        let (send_request, connection) = h2::client::handshake(client_io).await.unwrap();
+        tokio::spawn(connection);
+        send_request.ready().await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn endpoints_only_allow_get() {
+        let mut send_request = connect().await;
+        let request = Request::builder()
+            .method(Method::POST)
+            .uri("http://localhost/healthz")
+            .body(())
+            .unwrap();
+        let (response, _) = send_request.send_request(request, true).unwrap();
+        let response = response.await.unwrap();
+        assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(response.headers().get(ALLOW).unwrap(), "GET");
+    }
+
+    #[tokio::test]
+    async fn extended_connect_opens_a_websocket() {
+        let mut send_request = connect().await;
+        // the server allows extended CONNECT in its SETTINGS
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while !send_request.is_extended_connect_protocol_enabled() {
+                tokio::time::sleep(Duration::from_millis(1)).await;
+            }
+        }).await.unwrap();
+
+        let request = Request::builder()
+            .method(Method::CONNECT)
+            .uri("http://localhost/chat")
+            .header("sec-websocket-version", "13")
+            .header("user-id", "http2-test-chat")
+            .header("origin", "http://localhost")
+            .extension(Protocol::from_static("websocket"))
+            .body(())
+            .unwrap();
+        let (response, mut send_stream) = send_request.send_request(request, false).unwrap();
+        let response = response.await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        // a chat message to the user of the stream comes back on it, unmasked
+        let message = br#"{"sender_user_id":"http2-test-chat","message":"hello"}"#;
+        let mut frame_bytes = BytesMut::new();
+        FrameEncoder::new_client().encode(new_dataframe(Opcode::TextFrame, message.to_vec()), &mut frame_bytes).unwrap();
+        send_stream.send_data(frame_bytes.freeze(), false).unwrap();
+
+        let mut recv_stream = response.into_body();
+        let mut received = Vec::new();
+        while received.len() < 2 + message.len() {
+            let data = recv_stream.data().await.unwrap().unwrap();
+            let _ = recv_stream.flow_control().release_capacity(data.len());
+            received.extend_from_slice(&data);
+        }
+        assert_eq!(&received[..2], &[0x81, message.len() as u8]);
+        assert_eq!(&received[2..], message);
+    }
+}
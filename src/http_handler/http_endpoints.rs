@@ -0,0 +1,88 @@
+// Plain HTTP endpoints served on the websocket port, for the orchestrator
+// probing the service and for scraping metrics. A request for one of these
+// paths without an Upgrade header gets a normal response and the connection is
+// closed after it. Host is not checked, probes use the address of the pod.
+
+use std::collections::HashMap;
+use futures_util::future::BoxFuture;
+use http::{Request, Response, StatusCode};
+use http::header::{CACHE_CONTROL, CONNECTION, CONTENT_LENGTH, CONTENT_TYPE, DATE, HeaderValue, SERVER};
+use httpdate::fmt_http_date;
+use std::time::{Duration, SystemTime};
+use crate::{SERVICE_CONFIG, USER_ID_MAPPING};
+use crate::redis_client::RedisClient;
+use crate::admission;
+
+// a probe waiting longer than this gets a 503
+const REDIS_PING_TIMEOUT: Duration = Duration::from_secs(2);
+
+pub type EndpointHandler = fn(&Request<()>) -> BoxFuture<'static, Response<String>>;
+
+lazy_static! {
+    static ref HTTP_ENDPOINTS: HashMap<String, EndpointHandler> = {
+        let mut http_endpoints: HashMap<String, EndpointHandler> = HashMap::new();
+        http_endpoints.insert("/healthz".to_string(), healthz);
+        http_endpoints.insert("/readyz".to_string(), readyz);
+        http_endpoints.insert("/metrics".to_string(), metrics);
+        http_endpoints
+    };
+}
+
+pub fn find_endpoint(path: &str) -> Option<EndpointHandler> {
+    HTTP_ENDPOINTS.get(path).copied()
+}
+
+// process is up and accepting connections
+fn healthz(_request: &Request<()>) -> BoxFuture<'static, Response<String>> {
+    Box::pin(async {
+        text_response(StatusCode::OK, "text/plain; charset=utf-8", "ok\r\n".to_string())
+    })
+}
+
+// in cluster mode users can only be reached through Redis, a service which
+// can not talk to it should not get new connections
+fn readyz(_request: &Request<()>) -> BoxFuture<'static, Response<String>> {
+    Box::pin(async {
+        if SERVICE_CONFIG.cluster_mode {
+            // the client is synchronous, the ping runs off the runtime threads
+            let ping = tokio::task::spawn_blocking(|| RedisClient::ping(REDIS_PING_TIMEOUT).is_ok());
+            let redis_reachable = matches!(tokio::time::timeout(REDIS_PING_TIMEOUT, ping).await, Ok(Ok(true)));
+            if !redis_reachable {
+                crate::error!("Not ready, Redis not reachable");
+                return text_response(StatusCode::SERVICE_UNAVAILABLE, "text/plain; charset=utf-8", "redis not reachable\r\n".to_string());
+            }
+        }
+        text_response(StatusCode::OK, "text/plain; charset=utf-8", "ready\r\n".to_string())
+    })
+}
+
+// Prometheus text format
+fn metrics(_request: &Request<()>) -> BoxFuture<'static, Response<String>> {
+    Box::pin(async {
+        let connected_users = USER_ID_MAPPING.lock().await.len();
+        let body = format!(
+            "# HELP pollux_connected_users Users connected to this service.\n\
+             # TYPE pollux_connected_users gauge\n\
+             pollux_connected_users {}\n\
+             # HELP pollux_open_connections Connections counted against the connection cap.\n\
+             # TYPE pollux_open_connections gauge\n\
+             pollux_open_connections {}\n",
+            connected_users,
+            admission::open_connections()
+        );
+        text_response(StatusCode::OK, "text/plain; version=0.0.4", body)
+    })
+}
+
+fn text_response(status_code: StatusCode, content_type: &'static str, body: String) -> Response<String> {
+    Response::builder()
+        .status(status_code)
+        .header(CONTENT_TYPE, HeaderValue::from_static(content_type))
+        .header(CONTENT_LENGTH, body.len())
+        .header(CACHE_CONTROL, HeaderValue::from_static("no-store"))
+        .header(CONNECTION, HeaderValue::from_static("close"))
+        .header(SERVER, HeaderValue::from_static(super::SERVER_NAME))
+        .header(DATE, fmt_http_date(SystemTime::now()))
+        .body(body)
+        .unwrap()
+}
@@ -13,12 +13,33 @@ use http::header::{
     SEC_WEBSOCKET_PROTOCOL,
     SEC_WEBSOCKET_VERSION,
     SEC_WEBSOCKET_EXTENSIONS,
-    HOST
+    HOST,
+    WWW_AUTHENTICATE,
+    ALLOW,
+    CONTENT_TYPE,
+    CONTENT_LENGTH
 };
 use httpdate::fmt_http_date;
 use std::time::SystemTime;
+use std::sync::Arc;
+use std::net::IpAddr;
 use sha1::{Sha1, Digest};
 use crate::buffer::Buffer;
+use crate::permessage_deflate::{self, DeflateParams};
+use crate::route_table::{self, RouteMatch};
+use crate::authenticator::AUTHENTICATOR;
+use crate::origin_policy;
+use crate::trusted_proxy;
+use crate::listener;
+use crate::admission::{self, AdmissionPermit};
+use crate::service_config::ListenerConfig;
+use crate::sub_protocol::{self, SubProtocol};
+use crate::error::http_errors::{self, ErrorKind, HTTPError};
+
+#[cfg(test)]
+pub mod client_handshake;
+pub mod http_endpoints;
+pub mod http2;
 
 static SERVER_NAME: &str = "Cluster23";
 
@@ -32,29 +53,29 @@ static SERVER_NAME: &str = "Cluster23";
 // avoid potential collisions, it is recommended to use names that
 // contain the ASCII version of the domain name of the subprotocol's
 // originator
-static SUB_PROTOCOLS_SUPPORTED: [&str; 1] = ["v1.chat.cluster23.com"];
-static PATHS_ALLOWED: [&str; 1] = ["/chat"];
+//
+// subprotocols are registered in sub_protocol, a route lists the ones it accepts
 static GET_METHOD: &str = "GET";
-static WEBSOCKET_HEADERS_REQUIRED: [HeaderName;2] = [
-    SEC_WEBSOCKET_KEY, // 16 byte
-    // SEC_WEBSOCKET_PROTOCOL, if not present we will choose default
-    SEC_WEBSOCKET_VERSION
-];
 static WEBSOCKET_VERSION_SUPPORTED: &str = "13";
+// a handshake request with more headers gets a 431
+const MAX_HEADERS: usize = 64;
 use regex::Regex;
-lazy_static! {
-    static ref HEADER_REQUIRED_MAP : [(HeaderName,String);4] = [
-        // (ORIGIN,"cluster23.com"),
-        (HOST,crate::MY_ADDRESS.to_ascii_lowercase()),
-        (CONNECTION,"Upgrade".to_string()),
-        (UPGRADE,"websocket".to_string()),
-        (SEC_WEBSOCKET_VERSION,WEBSOCKET_VERSION_SUPPORTED.to_string())
-    ];
-}
 
 static GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
 
-pub fn get_http_response_bytes(response: Response<()>) -> Result<Vec<u8>,&'static str> {
+// what was agreed on during the handshake, needed to serve the connection
+pub struct HandshakeDetails {
+    pub user_id: String,
+    pub deflate_params: Option<DeflateParams>,
+    pub route_match: RouteMatch,
+    pub sub_protocol: Option<Arc<SubProtocol>>,
+    // address of the client, behind any trusted proxy
+    pub client_ip: IpAddr,
+    // counts the connection against the limits until it is dropped
+    pub admission_permit: AdmissionPermit
+}
+
+pub fn get_http_response_bytes(response: Response<String>) -> Result<Vec<u8>,&'static str> {
 
     let mut buffer: Box<Buffer<u8>> = crate::buffer::Buffer::new_unbound();
     let header_map = response.headers();
@@ -81,127 +102,90 @@ pub fn get_http_response_bytes(response: Response<()>) -> Result<Vec<u8>,&'stati
         buffer.append_u8_array(crfl_bytes)?;
     }
     buffer.append_u8_array(crfl_bytes)?;
+    buffer.append_u8_array(response.body().as_bytes())?;
     let arr_final = buffer.get_arr();
     Ok(arr_final)
 }
 
-fn select_sub_protocol(header_map: &HeaderMap) -> Result<&str,&'static str> {
-    // if header not present select the one available
-    let protocols_header = match  header_map.get(SEC_WEBSOCKET_PROTOCOL) {
-        None => { return Ok(SUB_PROTOCOLS_SUPPORTED[0])}
-        Some(val) => val
-    };
-    let protocols_requested = protocols_header.to_str().unwrap().split(",");
-    let mut protocol_matched: &str = "";
-    for protocol_str in protocols_requested {
-        if SUB_PROTOCOLS_SUPPORTED.contains(&protocol_str.trim()) {
-            protocol_matched = protocol_str;
-        }
-    }
-    if protocol_matched.len() > 0 {
-        return Ok(protocol_matched);
-    }
-    return Err("Not supported protocol found");
-}
-
-fn header_value_matching(header_map: &HeaderMap, header_name: &HeaderName, header_value_expected: &str) -> bool {
-    crate::info!("Checking \"{}\" Header Value",header_name.as_str());
-    match header_map.get(header_name) {
-        None => { return false}
-        Some(header_value) => {
-            if !header_value_expected.eq_ignore_ascii_case(header_value.to_str().unwrap()) {
-                crate::error!("Expected value: {} for header: {} but found: {}",header_value_expected,header_name.as_str(),header_value.to_str().unwrap());
-                return false;
-            }
-        }
-    }
-    true
-}
-
-pub fn can_be_upgraded_to_websocket(request: &Request<()> ) -> bool {
+// each failed check maps to the status of the response refusing the handshake
+pub fn can_be_upgraded_to_websocket(request: &Request<()>) -> http_errors::Result<()> {
     crate::info!("Checking Websocket handshake Request");
-    let mut path_matched = false;
-    crate::info!("Checking Path/Resource");
-    for path in PATHS_ALLOWED {
-        if request.uri().to_string().eq_ignore_ascii_case(path) {
-            path_matched = true;
-            break;
-        }
-    }
-
-    if !path_matched {
-        crate::error!("Path requested: \"{}\" not found",request.uri().to_string());
-        return false
-    }
 
+    //  The method of the request MUST be GET, and the HTTP version MUST
+    //    be at least 1.1.
     crate::info!("Checking HTTP Method");
     if !request.method().as_str().eq_ignore_ascii_case(GET_METHOD) {
         crate::error!("HTTP method: \"{}\" not allowed",request.method().as_str());
-        return false;
+        return Err(HTTPError::new(ErrorKind::MethodNotAllowed, "websocket handshake must use GET"));
     }
 
     let header_map = request.headers();
 
-    crate::info!("Checking Websocket Headers");
-    for header_req in WEBSOCKET_HEADERS_REQUIRED.iter() {
-        if !header_map.contains_key(header_req) {
-            crate::error!("Header \"{}\" not found",header_req.as_str());
-            return false;
-        }
+    //  An |Upgrade| header field containing the value "websocket",
+    //    treated as an ASCII case-insensitive value.
+    //
+    //  A |Connection| header field that includes the token "Upgrade",
+    //    treated as an ASCII case-insensitive value.
+    crate::info!("Checking Upgrade and Connection Headers");
+    if !header_has_token(header_map, &UPGRADE, "websocket") || !header_has_token(header_map, &CONNECTION, "upgrade") {
+        crate::error!("Request does not ask for an upgrade to websocket");
+        return Err(HTTPError::new(ErrorKind::UpgradeRequired, "upgrade to websocket required"));
     }
 
-    crate::info!("Checking Websocket Sub protocol");
-    if request.headers().contains_key(SEC_WEBSOCKET_PROTOCOL) {
-        match select_sub_protocol(header_map) {
-            Err(_) => {
-                crate::error!("Sub Protocol not supported ");
-                return false
-            }
-            _ => {}
+    check_websocket_version(header_map)?;
+
+    //  A |Sec-WebSocket-Key| header field with a base64-encoded (see
+    //    Section 4 of [RFC4648]) value that, when decoded, is 16 bytes in
+    //    length.
+    crate::info!("Checking Websocket Key");
+    let sec_ws_key = match header_map.get(SEC_WEBSOCKET_KEY).map(|header_value| header_value.to_str()) {
+        Some(Ok(sec_ws_key)) => sec_ws_key.trim(),
+        _ => {
+            crate::error!("Header \"{}\" not found",SEC_WEBSOCKET_KEY.as_str());
+            return Err(HTTPError::new(ErrorKind::BadRequest, "Sec-WebSocket-Key missing"));
         }
-    } else {
-        crate::info!("No sub-protocol Requested");
-    }
-
-    crate::info!("Checking HTTP headers");
-    for header_required in HEADER_REQUIRED_MAP.iter() {
-        if !header_value_matching(
-            header_map,
-            &header_required.0,
-            &header_required.1) {
-            return false;
+    };
+    match base64::decode(sec_ws_key) {
+        Ok(key_bytes) if key_bytes.len() == 16 => Ok(()),
+        _ => {
+            crate::error!("SEC_KEY should be 16 bytes encoded in base64");
+            Err(HTTPError::new(ErrorKind::BadRequest, "Sec-WebSocket-Key must be 16 bytes encoded in base64"))
         }
     }
-
-    let user_id_header: HeaderName = HeaderName::from_static("user-id");
-    crate::info!("Checking User Id Header: {}",user_id_header);
-    if !request.headers().contains_key(user_id_header) {
-        crate::error!("No User-id Header found");
-        return false;
-    }
-
-
-    true
 }
 
-pub fn create_websocket_response(request: Request<()>) -> Result<(Response<()>,String),&'static str> {
-    crate::info!("Creating Websocket handshake response");
-    if !can_be_upgraded_to_websocket(&request) {
-        crate::error!("Request not appropriate to make Handshake Successful");
-        return Err("Cannot be upgraded to websockets");
+//  If this version does not match a version understood by the server,
+//    the server MUST abort the WebSocket handshake described in this
+//    section and instead send an appropriate HTTP error code (such as
+//    426 Upgrade Required) and a |Sec-WebSocket-Version| header field
+//    indicating the version(s) the server is capable of understanding.
+pub fn check_websocket_version(header_map: &HeaderMap) -> http_errors::Result<()> {
+    crate::info!("Checking Websocket Version");
+    match header_map.get(SEC_WEBSOCKET_VERSION).map(|header_value| header_value.to_str()) {
+        Some(Ok(version)) if version.trim() == WEBSOCKET_VERSION_SUPPORTED => Ok(()),
+        _ => {
+            crate::error!("Websocket version: {:?} not supported",header_map.get(SEC_WEBSOCKET_VERSION));
+            Err(HTTPError::new(ErrorKind::UpgradeRequired, "websocket version not supported"))
+        }
     }
-    let mut response_builder = Response::builder();
-
-    // The |Sec-WebSocket-Accept| header field indicates whether
-    //    the server is willing to accept the connection
-    let sec_ws_key = request.headers().get(SEC_WEBSOCKET_KEY).unwrap().to_str().unwrap();
-    crate::info!("Creating Websocket Key response");
+}
 
-    if base64::decode(sec_ws_key).unwrap().len() != 16 {
-        crate::error!("SEC_KEY length should be 16 bytes");
-        return Err("SEC_KEY length should be 16 bytes");
-    }
+// header values are comma separated lists of tokens, the header can be sent more than once
+fn header_has_token(header_map: &HeaderMap, header_name: &HeaderName, token: &str) -> bool {
+    header_map.get_all(header_name).iter()
+        .filter_map(|header_value| header_value.to_str().ok())
+        .flat_map(|header_value| header_value.split(','))
+        .any(|header_token| header_token.trim().eq_ignore_ascii_case(token))
+}
 
+//  For this header field, the server has to take the value (as present
+//    in the header field, e.g., the base64-encoded [RFC4648] version minus
+//    any leading and trailing whitespace) and concatenate this with the
+//    Globally Unique Identifier (GUID, [RFC4122]) "258EAFA5-E914-47DA-
+//    95CA-C5AB0DC85B11" in string form. A SHA-1 hash (160 bits)
+//    [FIPS.180-3], base64-encoded, of this concatenation is then returned
+//    in the server's handshake.
+pub fn create_accept_key(sec_ws_key: &str) -> String {
     let concat_key = format!("{}{}", sec_ws_key, GUID);
     let concat_key_u8 = concat_key.as_bytes();
     crate::info!("concat_key: {}",concat_key);
@@ -213,6 +197,49 @@ pub fn create_websocket_response(request: Request<()>) -> Result<(Response<()>,S
 
     let encoded_sha1 = base64::encode(encoded_arr);
     crate::info!("base64 hash: {}",encoded_sha1);
+    encoded_sha1
+}
+
+// a request without an Upgrade header for one of the HTTP endpoints gets a
+// normal response, every other request is treated as a websocket handshake
+pub async fn handle_http_request(request: Request<()>, peer_ip: IpAddr, listener: &ListenerConfig, connection_admitted: bool) -> http_errors::Result<(Response<String>,Option<HandshakeDetails>)> {
+    if !header_has_token(request.headers(), &UPGRADE, "websocket") {
+        if let Some(endpoint) = http_endpoints::find_endpoint(request.uri().path()) {
+            crate::info!("Serving HTTP endpoint: {}",request.uri().path());
+            if !request.method().as_str().eq_ignore_ascii_case(GET_METHOD) {
+                return Err(HTTPError::new(ErrorKind::MethodNotAllowed, "only GET is allowed"));
+            }
+            return Ok((endpoint(&request).await, None));
+        }
+    }
+    let (http_resp, handshake_details) = create_websocket_response(request, peer_ip, listener, connection_admitted)?;
+    Ok((http_resp, Some(handshake_details)))
+}
+
+pub fn create_websocket_response(request: Request<()>, peer_ip: IpAddr, listener: &ListenerConfig, connection_admitted: bool) -> http_errors::Result<(Response<String>,HandshakeDetails)> {
+    crate::info!("Creating Websocket handshake response");
+    //  A server MUST respond with a 400 (Bad Request) status code to any
+    //    HTTP/1.1 request message that lacks a Host header field and to any
+    //    that contains more than one Host header field or a Host header field
+    //    with an invalid field-value.
+    crate::info!("Checking Host");
+    let mut host_values = request.headers().get_all(HOST).iter();
+    let host = match (host_values.next().map(|header_value| header_value.to_str()), host_values.next()) {
+        (Some(Ok(host)), None) => host.trim(),
+        _ => {
+            crate::error!("Host header missing, repeated or invalid");
+            return Err(HTTPError::new(ErrorKind::BadRequest, "invalid host header"));
+        }
+    };
+    let route_match = match_host_and_route(listener, host, request.uri())?;
+    can_be_upgraded_to_websocket(&request)?;
+    let mut response_builder = Response::builder();
+
+    // The |Sec-WebSocket-Accept| header field indicates whether
+    //    the server is willing to accept the connection
+    let sec_ws_key = request.headers().get(SEC_WEBSOCKET_KEY).unwrap().to_str().unwrap().trim();
+    crate::info!("Creating Websocket Key response");
+    let encoded_sha1 = create_accept_key(sec_ws_key);
 
 
     // any status code other than 101 indicates that the WebSocket handshake
@@ -237,80 +264,153 @@ pub fn create_websocket_response(request: Request<()>) -> Result<(Response<()>,S
         .header(SERVER,HeaderValue::from_static(SERVER_NAME))
         .header(DATE,fmt_http_date(SystemTime::now()));
 
-    //if a sub prototcol is requested
-    if request.headers().contains_key(SEC_WEBSOCKET_PROTOCOL) {
-        response_builder = response_builder.header(SEC_WEBSOCKET_PROTOCOL,HeaderValue::from_str(select_sub_protocol(request.headers()).unwrap()).unwrap())
+    let (response_builder, handshake_details) = accept_websocket(&request, peer_ip, listener, connection_admitted, route_match, response_builder)?;
+    Ok((response_builder.body(String::new()).unwrap(),handshake_details))
+}
+
+pub fn match_host_and_route(listener: &ListenerConfig, host: &str, uri: &Uri) -> http_errors::Result<RouteMatch> {
+    if !route_table::is_known_host(listener, host) {
+        crate::error!("Host: \"{}\" is not served on listener \"{}\"",host,listener.name);
+        return Err(HTTPError::new(ErrorKind::BadRequest, "unknown host"));
+    }
+
+    crate::info!("Checking Path/Resource");
+    match route_table::match_route(listener, host, uri) {
+        Some(route_match) => Ok(route_match),
+        None => {
+            crate::error!("Path requested: \"{}\" not found",uri.path());
+            Err(HTTPError::new(ErrorKind::NotFound, "path not found"))
+        }
+    }
+}
+
+// checks and negotiation done the same way whether the websocket is opened by
+// an HTTP/1.1 upgrade or an HTTP/2 extended CONNECT, negotiated headers are
+// added to the response
+pub fn accept_websocket(request: &Request<()>, peer_ip: IpAddr, listener: &ListenerConfig, connection_admitted: bool, route_match: RouteMatch, mut response_builder: http::response::Builder) -> http_errors::Result<(http::response::Builder,HandshakeDetails)> {
+    // only a proxy on this host can connect to a Unix domain socket, it is
+    // trusted without being in the configured CIDRs
+    let peer_trusted = listener::unix_socket_path(listener).is_some() || trusted_proxy::is_trusted(peer_ip);
+    let client_ip = trusted_proxy::client_ip(request.headers(), peer_ip, peer_trusted);
+    crate::info!("Client address: {}",client_ip);
+
+    crate::info!("Checking Origin");
+    origin_policy::check_origin(request, &crate::SERVICE_CONFIG.origin_policy)?;
+
+    // no subprotocol is picked for a client which did not ask for one
+    let sub_protocol = sub_protocol::negotiate(request.headers(), &route_match.route.sub_protocols);
+    match &sub_protocol {
+        Some(sub_protocol) => {
+            crate::info!("Sub protocol selected: {}",sub_protocol.name);
+            response_builder = response_builder.header(SEC_WEBSOCKET_PROTOCOL,HeaderValue::from_str(&sub_protocol.name).unwrap());
+        }
+        None => crate::info!("No sub-protocol selected")
+    }
+
+    let deflate_params = permessage_deflate::negotiate(request.headers(), &crate::SERVICE_CONFIG.permessage_deflate);
+    if let Some(deflate_params) = &deflate_params {
+        response_builder = response_builder.header(SEC_WEBSOCKET_EXTENSIONS,HeaderValue::from_str(&deflate_params.response_header_value()).unwrap());
     }
 
-    let user_id = request.headers().get(HeaderName::from_static("user-id"))
-        .unwrap()
-        .to_str().unwrap()
-        .to_string();
+    let identity = AUTHENTICATOR.authenticate(request, &route_match)?;
+    let user_id = identity.user_id;
 
     let re = Regex::new(r"^[A-Za-z\d\-_]+$").unwrap();
     if !re.is_match(&user_id) {
         crate::error!("User_id format is invalid");
-        return Err("User_id format is invalid");
+        return Err(HTTPError::new(ErrorKind::Forbidden, "user id format is invalid"));
     }
 
+    // limits are checked last, a handshake refused for another reason is not counted
+    let admission_permit = admission::admit(client_ip, connection_admitted, &crate::SERVICE_CONFIG.admission)?;
+
     // The server can also set cookie-related option fields to _set_
     //    cookies, as described in [RFC6265].
-    Ok((response_builder.body(()).unwrap(),user_id))
+    Ok((response_builder,HandshakeDetails { user_id, deflate_params, route_match, sub_protocol, client_ip, admission_permit }))
 }
 
-pub fn create_401_response() -> Response<()>{
-    Response::builder()
-        .header(SEC_WEBSOCKET_VERSION,HeaderValue::from_static("13"))
-        .status(StatusCode::BAD_REQUEST)
-        .body(())
-        .unwrap()
+// reason of the refusal is sent as a short plain text body, the connection is
+// closed after it
+pub fn create_error_response(http_error: &HTTPError) -> Response<String>{
+    let body = format!("{}\r\n",http_error.message());
+    let mut response_builder = Response::builder()
+        .status(http_error.status_code())
+        .header(CONTENT_TYPE,HeaderValue::from_static("text/plain; charset=utf-8"))
+        .header(CONTENT_LENGTH,body.len())
+        .header(CONNECTION,HeaderValue::from_static("close"))
+        .header(SERVER,HeaderValue::from_static(SERVER_NAME))
+        .header(DATE,fmt_http_date(SystemTime::now()));
+    match http_error.status_code() {
+        //  A server generating a 401 (Unauthorized) response MUST send a
+        //    WWW-Authenticate header field containing at least one challenge
+        StatusCode::UNAUTHORIZED => {
+            response_builder = response_builder.header(WWW_AUTHENTICATE,HeaderValue::from_static("Bearer"));
+        }
+        //  The origin server MUST generate an Allow header field in a 405
+        //    response containing a list of the target resource's currently
+        //    supported methods.
+        StatusCode::METHOD_NOT_ALLOWED => {
+            response_builder = response_builder.header(ALLOW,HeaderValue::from_static(GET_METHOD));
+        }
+        //  The server MUST send an Upgrade header field in a 426 response to
+        //    indicate the required protocol(s)
+        StatusCode::UPGRADE_REQUIRED => {
+            response_builder = response_builder
+                .header(UPGRADE,HeaderValue::from_static("websocket"))
+                .header(SEC_WEBSOCKET_VERSION,HeaderValue::from_static(WEBSOCKET_VERSION_SUPPORTED));
+        }
+        _ => {}
+    }
+    response_builder.body(body).unwrap()
 }
 
 
-pub fn parse_http_request_bytes(bytes: Vec<u8>) -> Result<Request<()>,&'static str> {
+// returns the request and the length of its head, None if the head is not complete yet
+pub fn parse_http_request_bytes(bytes: &[u8]) -> http_errors::Result<Option<(Request<()>, usize)>> {
     crate::info!("Parsing HTTP request");
 
-    let mut headers = [EMPTY_HEADER;30];
+    let mut headers = [EMPTY_HEADER;MAX_HEADERS];
     let mut http_parser_req = httparse::Request::new(&mut headers);
-    match http_parser_req.parse(&bytes) {
-        Ok(status) => {
-            match status {
-                Status::Complete(_headers) => {
-                    crate::info!("Parsing complete");
-                }
-                Status::Partial => {
-                    crate::error!("Not able to completely parse request ");
-                    return Err("Partial parsed request");
-                }
-            }
+    let head_length = match http_parser_req.parse(bytes) {
+        Ok(Status::Complete(head_length)) => {
+            crate::info!("Parsing complete");
+            head_length
+        }
+        Ok(Status::Partial) => return Ok(None),
+        Err(httparse::Error::TooManyHeaders) => {
+            crate::error!("Http request has more than {} headers",MAX_HEADERS);
+            return Err(HTTPError::new(ErrorKind::RequestHeaderFieldsTooLarge, "too many headers"));
         }
         Err(e) => {
             crate::error!("Error while parsing http request: {} ",e);
-            return Err("Error while parsing http request");
+            return Err(HTTPError::new(ErrorKind::BadRequest, "Error while parsing http request"));
         }
     };
 
-
     if http_parser_req.version.is_none() {
         crate::error!("HTTP Version not found");
-        return Err("Http Version not found");
+        return Err(HTTPError::new(ErrorKind::BadRequest, "Http Version not found"));
     }
 
     if http_parser_req.method.is_none() {
         crate::error!("Http method not found");
-        return Err("Http method not found");
+        return Err(HTTPError::new(ErrorKind::BadRequest, "Http method not found"));
     }
 
     if http_parser_req.path.is_none() {
         crate::error!("Http request Path not found");
-        return Err("Http request Path not found");
+        return Err(HTTPError::new(ErrorKind::BadRequest, "Http request Path not found"));
     }
 
+    let uri = match Uri::from_str(http_parser_req.path.unwrap()) {
+        Ok(uri) => uri,
+        Err(e) => {
+            crate::error!("Error occurred: {}",e);
+            return Err(HTTPError::new(ErrorKind::BadRequest, "Invalid request path"));
+        }
+    };
     let mut http_request_builder = Request::builder()
-        .uri(
-            Uri::from_str(http_parser_req.path.unwrap())
-                .unwrap()
-        )
+        .uri(uri)
         .method(
             &Method::from_bytes(http_parser_req
                 .method.unwrap()
@@ -319,13 +419,13 @@ pub fn parse_http_request_bytes(bytes: Vec<u8>) -> Result<Request<()>,&'static s
         )
         .version(Version::HTTP_11);
 
-    for header in headers {
+    for header in http_parser_req.headers.iter() {
         if !header.name.is_empty() {
             let header_name = match HeaderName::from_bytes(header.name.to_ascii_lowercase().as_bytes()) {
                 Ok(header_name) => header_name,
                 Err(e) => {
                     crate::error!("Error occurred: {}",e);
-                    return Err("InvalidHeaderName")
+                    return Err(HTTPError::new(ErrorKind::BadRequest, "InvalidHeaderName"))
                 }
             };
 
@@ -333,19 +433,15 @@ pub fn parse_http_request_bytes(bytes: Vec<u8>) -> Result<Request<()>,&'static s
                 Ok(header_value) => header_value,
                 Err(e) => {
                     crate::error!("Error occurred: {}",e);
-                    return Err("InvalidHeaderValue")
+                    return Err(HTTPError::new(ErrorKind::BadRequest, "InvalidHeaderValue"))
                 }
             };
             http_request_builder = http_request_builder.header(header_name, header_value);
         }
     }
 
+    // method is checked with the rest of the handshake, a wrong one gets a 405
     let http_request = http_request_builder.body(()).unwrap();
-    if !http_request.method().as_str().trim().eq_ignore_ascii_case(GET_METHOD) {
-        crate::error!("Http Method not support");
-        return Err("Only HTTP GET method is allowed");
-    }
-
-    Ok(http_request)
+    Ok(Some((http_request, head_length)))
 
 }
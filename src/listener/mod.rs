@@ -0,0 +1,92 @@
+// Sockets connections are accepted on. Each listener is a TCP address or a
+// Unix domain socket, for a proxy running on the same host, and every one of
+// them feeds the same connection pipeline with its own settings.
+
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::net::SocketAddr;
+use std::os::unix::fs::FileTypeExt;
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::net::{TcpListener, UnixListener};
+use crate::service_config::ListenerConfig;
+
+static UNIX_PREFIX: &str = "unix:";
+const BACKLOG: i32 = 1024;
+
+lazy_static! {
+    pub static ref LISTENERS: Vec<ListenerConfig> = {
+        let listeners = if crate::SERVICE_CONFIG.listeners.is_empty() {
+            vec![default_listener()]
+        } else {
+            crate::SERVICE_CONFIG.listeners.clone()
+        };
+        let mut names = HashSet::new();
+        for listener in listeners.iter() {
+            if !names.insert(listener.name.as_str()) {
+                panic!("Listener \"{}\" declared more than once",listener.name);
+            }
+            if listener.tls && !crate::SERVICE_CONFIG.tls.enabled {
+                panic!("Listener \"{}\" uses TLS but the tls section is disabled",listener.name);
+            }
+        }
+        listeners
+    };
+}
+
+// the random local address used before listeners could be configured
+fn default_listener() -> ListenerConfig {
+    ListenerConfig {
+        name: "default".to_string(),
+        address: crate::MY_ADDRESS.to_ascii_lowercase(),
+        dual_stack: true,
+        tls: crate::SERVICE_CONFIG.tls.enabled,
+        routes: None,
+        host_names: vec!["*".to_string()],
+        size_limits: None
+    }
+}
+
+pub enum BoundListener {
+    Tcp(TcpListener),
+    Unix(UnixListener)
+}
+
+pub fn unix_socket_path(listener: &ListenerConfig) -> Option<&str> {
+    listener.address.strip_prefix(UNIX_PREFIX)
+}
+
+pub fn bind(listener: &ListenerConfig) -> io::Result<BoundListener> {
+    match unix_socket_path(listener) {
+        Some(path) => bind_unix(path).map(BoundListener::Unix),
+        None => bind_tcp(listener).map(BoundListener::Tcp)
+    }
+}
+
+// tokio binds an IPv6 address with the system default for IPV6_V6ONLY, the
+// socket is set up here so dual stack does not depend on the host
+fn bind_tcp(listener: &ListenerConfig) -> io::Result<TcpListener> {
+    let address: SocketAddr = listener.address.parse()
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "listener address is not host:port"))?;
+    let socket = Socket::new(Domain::for_address(address), Type::STREAM, Some(Protocol::TCP))?;
+    if address.is_ipv6() {
+        socket.set_only_v6(!listener.dual_stack)?;
+    }
+    socket.set_reuse_address(true)?;
+    socket.bind(&address.into())?;
+    socket.listen(BACKLOG)?;
+    socket.set_nonblocking(true)?;
+    TcpListener::from_std(socket.into())
+}
+
+// the socket file of an earlier run is left behind when it did not stop
+// cleanly, anything else at the path is not ours to remove
+fn bind_unix(path: &str) -> io::Result<UnixListener> {
+    if let Ok(metadata) = fs::symlink_metadata(path) {
+        if !metadata.file_type().is_socket() {
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists, "listener path exists and is not a socket"));
+        }
+        fs::remove_file(path)?;
+    }
+    UnixListener::bind(path)
+}
@@ -1,20 +1,22 @@
-use std::net::SocketAddr;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 use log::{info, error, LevelFilter};
 use log4rs::Config;
 use log4rs;
 use log4rs::append::console::ConsoleAppender;
 use log4rs::config::{Appender, Root};
 use log4rs::encode::pattern::PatternEncoder;
-use tokio::io::AsyncWriteExt;
-use tokio::net::{TcpListener, TcpStream};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpStream, UnixStream};
+use tokio::time::Instant;
 
-use http::{Request, StatusCode};
-use crate::data_frame::{Opcode, DataFrameInfo, ReadFrom};
-use crate::model::{Message};
-use crate::service_config::{ServiceConfig};
-use tokio::sync::{mpsc, Mutex};
-use tokio::net::tcp::{OwnedReadHalf,OwnedWriteHalf};
-use tokio::sync::mpsc::Receiver;
+use http::StatusCode;
+use bytes::BytesMut;
+use crate::data_frame::DataFrameInfo;
+use crate::service_config::{ListenerConfig, ServiceConfig};
+use crate::admission::ConnectionPermit;
+use crate::listener::BoundListener;
+use futures_util::future::join_all;
+use tokio::sync::Mutex;
 use std::collections::HashMap;
 use tokio::sync::mpsc::Sender;
 use redis_client::{RedisClient};
@@ -34,6 +36,22 @@ mod redis_client;
 mod channel_handler;
 mod workers;
 mod service_config;
+mod fragment_handler;
+mod permessage_deflate;
+mod utf8_validator;
+mod heartbeat;
+mod connection_handler;
+// client mode has no entry point in the binary yet, only the tests use it
+#[cfg(test)]
+mod client;
+mod route_table;
+mod authenticator;
+mod origin_policy;
+mod sub_protocol;
+mod trusted_proxy;
+mod tls;
+mod admission;
+mod listener;
 
 //           ws-URI = "ws:" "//" host [ ":" port ] path [ "?" query ]
 //           wss-URI = "wss:" "//" host [ ":" port ] path [ "?" query ]
@@ -46,7 +64,7 @@ mod service_config;
 //           while the default for "wss" is port 443.
 
 lazy_static! {
-    static ref USER_ID_MAPPING: Mutex<HashMap<String,Sender<Vec<u8>>>> = {
+    static ref USER_ID_MAPPING: Mutex<HashMap<String,Sender<DataFrameInfo>>> = {
         let mut m = Mutex::new(HashMap::new());
         m
     };
@@ -60,8 +78,10 @@ lazy_static! {
         format!("{}{}","127.0.0.1:",rand::random::<u16>())
     };
     static ref SERVICE_CONFIG: ServiceConfig = {
-        let mut m = service_config::get_default_config();
-        m
+        match std::env::var("POLLUX_ENV") {
+            Ok(env) => service_config::new_config(env),
+            Err(_) => service_config::get_default_config()
+        }
     };
 }
 
@@ -78,11 +98,36 @@ async fn main() {
         .unwrap();
 
     let _handle = log4rs::init_config(config).unwrap();
+    // the statics built from the config are initialized before accepting
+    // connections, a bad key file, CIDR, certificate or listener stops the
+    // server here instead of failing the first connection
+    lazy_static::initialize(&authenticator::AUTHENTICATOR);
+    lazy_static::initialize(&trusted_proxy::TRUSTED_CIDRS);
+    lazy_static::initialize(&listener::LISTENERS);
+    if listener::LISTENERS.iter().any(|listener| listener.tls) {
+        lazy_static::initialize(&tls::TLS_ACCEPTOR);
+        if SERVICE_CONFIG.tls.reload_interval_secs > 0 {
+            tokio::spawn(async move {
+                tls::watch_certificate_files(&SERVICE_CONFIG.tls).await;
+            });
+        }
+    }
     info!("My Address, {}",MY_ADDRESS.to_ascii_lowercase());
-    // Bind the listener to the address
-    let listener = TcpListener::bind(MY_ADDRESS.to_ascii_lowercase()).await.unwrap();
+    // every listener is bound before any connection is accepted
+    let bound_listeners: Vec<(&'static ListenerConfig, BoundListener)> = listener::LISTENERS.iter()
+        .map(|listener| match listener::bind(listener) {
+            Ok(bound_listener) => (listener, bound_listener),
+            Err(e) => panic!("Not able to bind listener \"{}\" to {}: {}",listener.name,listener.address,e)
+        })
+        .collect();
 
     if SERVICE_CONFIG.cluster_mode {
+        if SERVICE_CONFIG.cluster_tls.enabled {
+            lazy_static::initialize(&tls::cluster::CLUSTER_TLS_ACCEPTOR);
+            lazy_static::initialize(&tls::cluster::CLUSTER_TLS_CONNECTOR);
+        } else {
+            error!("Cluster TLS is disabled, messages between services are not authenticated");
+        }
         match RedisClient::initialize_redis_connection().await {
             Ok(redis_client) => {
                 REDIS_CLIENT.lock().await.insert(redis_client);
@@ -96,191 +141,173 @@ async fn main() {
         });
     }
 
-    loop {
-        // The second item contains the IP and port of the new connection.
-        info!("Waiting for Clients at Address: {}",MY_ADDRESS.to_ascii_lowercase());
-        let (socket, socket_address) = listener.accept().await.unwrap();
-        info!("Client connected : {:?}", socket_address);
-        tokio::spawn(async move{
-            process(socket, socket_address).await;
-        });
-    }
+    join_all(bound_listeners.into_iter().map(|(listener, bound_listener)| accept_connections(listener, bound_listener))).await;
 }
-// The server MUST close the connection upon receiving a
-//    frame that is not masked. In this case, a server MAY send a Close
-//    frame with a status code of 1002 (protocol error)
-async fn process(socket: TcpStream, _ip: SocketAddr )  {
-    info!("Processing TcpStream: Start");
-    let ( mut read_half, mut write_half) = socket.into_split();
-
-    let bytes = tcp_handler::read_bytes_from_socket(&mut read_half).await.unwrap();
-    println!("{}", std::str::from_utf8(&bytes).unwrap());
-    let http_request: Request<()> = http_handler::parse_http_request_bytes(bytes).unwrap();
 
-    // handshake
-    let (http_resp,user_id) = http_handler::create_websocket_response(http_request).unwrap_or_else(|_error| {
-        return (http_handler::create_401_response(),"".to_owned());
-    });
-    let http_response_status = http_resp.status().clone();
-    let http_resp_bytes = http_handler::get_http_response_bytes(http_resp);
-    match write_half.write(&*http_resp_bytes.unwrap()).await {
-        Ok(n) => info!("Data sent size: {}",n),
-        Err(e) => {
-            error!("Enable to send Data : {}",e);
-            return;
+async fn accept_connections(listener: &'static ListenerConfig, bound_listener: BoundListener) {
+    let mut accept_backoff = admission::AcceptBackoff::new(&SERVICE_CONFIG.admission);
+    match bound_listener {
+        BoundListener::Tcp(tcp_listener) => loop {
+            // The second item contains the IP and port of the new connection.
+            info!("Waiting for Clients at Address: {}",listener.address);
+            let (socket, socket_address) = match tcp_listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    error!("Not able to accept on listener \"{}\": {}",listener.name,e);
+                    accept_backoff.wait().await;
+                    continue;
+                }
+            };
+            accept_backoff.reset();
+            let connection_permit = admission::admit_connection(&SERVICE_CONFIG.admission);
+            if connection_permit.is_none() && admission::closes_when_limited(&SERVICE_CONFIG.admission) {
+                error!("Connection cap reached, closing connection from {:?}",socket_address);
+                continue;
+            }
+            info!("Client connected on \"{}\": {:?}",listener.name,socket_address);
+            tokio::spawn(async move{
+                process(socket, socket_address, listener, connection_permit).await;
+            });
+        },
+        BoundListener::Unix(unix_listener) => loop {
+            info!("Waiting for Clients at Address: {}",listener.address);
+            let socket = match unix_listener.accept().await {
+                Ok((socket, _)) => socket,
+                Err(e) => {
+                    error!("Not able to accept on listener \"{}\": {}",listener.name,e);
+                    accept_backoff.wait().await;
+                    continue;
+                }
+            };
+            accept_backoff.reset();
+            let connection_permit = admission::admit_connection(&SERVICE_CONFIG.admission);
+            if connection_permit.is_none() && admission::closes_when_limited(&SERVICE_CONFIG.admission) {
+                error!("Connection cap reached, closing connection on \"{}\"",listener.name);
+                continue;
+            }
+            info!("Client connected on \"{}\"",listener.name);
+            tokio::spawn(async move{
+                process_unix(socket, listener, connection_permit).await;
+            });
         }
-    };
-    if http_response_status != StatusCode::SWITCHING_PROTOCOLS{
-        info!("Connection Unsuccessful, Dropping thread");
-        return;
     }
+}
 
-    let (tx, mut rx) = mpsc::channel(100);
-    USER_ID_MAPPING.lock().await.insert(user_id.clone(),tx.clone());
-    if SERVICE_CONFIG.cluster_mode {
-        REDIS_CLIENT.lock().await.as_mut().unwrap().set(user_id.clone(),TCP_WORKER_ADDRESS.to_ascii_lowercase()).unwrap();
+// the permit is held until the connection is closed, without one the
+// connection is only kept to refuse its handshake
+async fn process(mut socket: TcpStream, socket_address: SocketAddr, listener: &'static ListenerConfig, connection_permit: Option<ConnectionPermit>)  {
+    info!("Processing TcpStream: Start");
+    let connection_admitted = connection_permit.is_some();
+    if let Err(e) = tcp_handler::set_socket_options(&socket, &SERVICE_CONFIG.tcp) {
+        error!("Not able to set socket options for {}: {}",socket_address,e);
     }
-
-    loop {
-        let mut data_frame = data_frame::get_default_dataframe();
-        tokio::select! {
-            val = data_frame::read_next_dataframe_from_socket(&mut read_half) => {
-                data_frame = val;
+    let handshake_deadline = tcp_handler::handshake_deadline(&SERVICE_CONFIG.handshake);
+    // behind a load balancer the peer is the balancer, the client comes in the PROXY header
+    let mut peer_ip = socket_address.ip();
+    if SERVICE_CONFIG.trusted_proxies.proxy_protocol && trusted_proxy::is_trusted(peer_ip) {
+        match trusted_proxy::proxy_protocol::read_proxy_header(&mut socket, handshake_deadline).await {
+            Ok(Some(source_address)) => {
+                info!("PROXY header received, client: {}",source_address);
+                peer_ip = source_address.ip();
             }
-            val = data_frame::read_dataframe_from_rx(&mut rx) => {
-                data_frame = val;
+            Ok(None) => info!("PROXY header received without client address"),
+            Err(e) => {
+                error!("Dropping connection from {}: {}",socket_address,e);
+                return;
             }
         }
-        let (close_connection, vec_to_send) = process_frame(&data_frame, &mut rx, &mut read_half).await;
-        if close_connection {
-            break;
-        }
-        let message: Message = serde_json::from_slice(&vec_to_send[1][..]).unwrap();
-        send_response_frame(&data_frame, vec_to_send, message, &mut write_half).await;
     }
-
-    info!("Removing entry from hashMap");
-    USER_ID_MAPPING.lock().await.remove(&user_id);
-    if SERVICE_CONFIG.cluster_mode {
-        info!("Removing entry from Redis: End");
-        REDIS_CLIENT.lock().await.as_mut().unwrap().delete(user_id);
+    if listener.tls {
+        serve_tls_connection(socket, peer_ip, listener, handshake_deadline, connection_admitted).await;
+    } else if SERVICE_CONFIG.http2.enabled && tcp_handler::starts_with_http2_preface(&socket, handshake_deadline).await {
+        http_handler::http2::serve_http2_connection(socket, &SERVICE_CONFIG.http2, peer_ip, listener, handshake_deadline, connection_admitted).await;
+    } else {
+        let (read_half, write_half) = socket.into_split();
+        serve_http1_connection(read_half, write_half, peer_ip, listener, handshake_deadline, connection_admitted).await;
     }
     info!("Processing TcpStream: End");
 }
 
-async fn process_frame(data_frame: &DataFrameInfo,rx: &mut Receiver<Vec<u8>>, read_half: &mut OwnedReadHalf) -> (bool,Vec<Vec<u8>>) {
-    return match data_frame.opcode {
-        Opcode::TextFrame => {
-            (false, process_text_frame(data_frame, rx, read_half).await)
-        }
-        Opcode::Ping => {
-            (false, process_ping_frame(data_frame, rx, read_half).await)
-        }
-        Opcode::BinaryFrame => {
-            (false, process_binary_frame(data_frame, rx, read_half).await)
-        }
-        Opcode::ConnectionClose => {
-            info!("Close Connection Opcode received");
-            (true, Vec::new() )
-        }
-        _ => {
-            error!("Opcode not supported");
-            (true, Vec::new())
-        }
-    };
-}
-
-async fn process_text_frame(data_frame: &DataFrameInfo,rx: &mut Receiver<Vec<u8>>, read_half: &mut OwnedReadHalf) -> Vec<Vec<u8>> {
-    let mut vec_to_send: Vec<Vec<u8>> = Vec::new();
-    let mut text_data = data_frame.payload_data.clone();
-    match data_frame.read_from {
-        ReadFrom::Socket => {
-            data_frame::mask_unmask_data(&mut text_data, &data_frame.mask_key);
-            vec_to_send.push(data_frame::create_text_frame_with_data_length(text_data.len()));
-        }
-        ReadFrom::Channel => {
-            info!("message received from Channel");
-            vec_to_send.push(data_frame.raw_bytes.clone());
-        }
+// the proxy in front of a Unix domain socket runs on this host and is trusted,
+// the client address comes in its forwarding headers. 127.0.0.1 stands for the
+// proxy when it sends none.
+async fn process_unix(socket: UnixStream, listener: &'static ListenerConfig, connection_permit: Option<ConnectionPermit>) {
+    info!("Processing UnixStream: Start");
+    let connection_admitted = connection_permit.is_some();
+    let handshake_deadline = tcp_handler::handshake_deadline(&SERVICE_CONFIG.handshake);
+    let peer_ip = IpAddr::V4(Ipv4Addr::LOCALHOST);
+    if listener.tls {
+        serve_tls_connection(socket, peer_ip, listener, handshake_deadline, connection_admitted).await;
+    } else {
+        let (read_half, write_half) = socket.into_split();
+        serve_http1_connection(read_half, write_half, peer_ip, listener, handshake_deadline, connection_admitted).await;
     }
-    vec_to_send.push(text_data);
-    return vec_to_send
+    info!("Processing UnixStream: End");
 }
 
-async fn process_binary_frame(data_frame: &DataFrameInfo,rx: &mut Receiver<Vec<u8>>, read_half: &mut OwnedReadHalf) -> Vec<Vec<u8>> {
-    let mut vec_to_send: Vec<Vec<u8>> = Vec::new();
-    let mut binary_data = data_frame.payload_data.clone();
-    match data_frame.read_from {
-        ReadFrom::Socket => {
-            data_frame::mask_unmask_data(&mut binary_data, &data_frame.mask_key);
-            vec_to_send.push(data_frame::create_binary_frame_with_data_length(binary_data.len()));
+async fn serve_tls_connection<S>(socket: S, peer_ip: IpAddr, listener: &'static ListenerConfig, handshake_deadline: Instant, connection_admitted: bool)
+    where S: AsyncRead + AsyncWrite + Unpin {
+    let tls_stream = match tokio::time::timeout_at(handshake_deadline, tls::TLS_ACCEPTOR.accept(socket)).await {
+        Ok(Ok(tls_stream)) => tls_stream,
+        Ok(Err(e)) => {
+            error!("TLS handshake failed: {}",e);
+            return;
         }
-        ReadFrom::Channel => {
-            info!("message received from Channel");
-            vec_to_send.push(data_frame.raw_bytes.clone());
+        Err(_) => {
+            error!("TLS handshake not done in {}s",SERVICE_CONFIG.handshake.timeout_secs);
+            return;
         }
+    };
+    // over TLS the protocol is agreed with ALPN, there is no preface to look for
+    if tls_stream.get_ref().1.alpn_protocol() == Some(b"h2") {
+        http_handler::http2::serve_http2_connection(tls_stream, &SERVICE_CONFIG.http2, peer_ip, listener, handshake_deadline, connection_admitted).await;
+    } else {
+        let (read_half, write_half) = tokio::io::split(tls_stream);
+        serve_http1_connection(read_half, write_half, peer_ip, listener, handshake_deadline, connection_admitted).await;
     }
-    vec_to_send.push(binary_data);
-    return vec_to_send
-}
-
-async fn process_ping_frame(data_frame: &DataFrameInfo,rx: &mut Receiver<Vec<u8>>, read_half: &mut OwnedReadHalf) -> Vec<Vec<u8>> {
-    let mut vec_to_send: Vec<Vec<u8>> = Vec::new();
-    let mut ping_data = data_frame.payload_data.clone();
-    data_frame::mask_unmask_data(&mut ping_data, &data_frame.mask_key);
-    vec_to_send.push(data_frame::create_pong_frame(ping_data.len()));
-    vec_to_send.push(ping_data);
-    return vec_to_send
 }
 
-async fn send_response_frame(data_frame: &DataFrameInfo, vec_to_send: Vec<Vec<u8>>, message: Message, write_half: &mut OwnedWriteHalf) {
-    match data_frame.read_from {
-        ReadFrom::Socket => {
-            match data_frame.opcode {
-                Opcode::Ping => send_reply_arrived_to_this_user(vec_to_send,write_half).await,
-                _ => {
-                    match USER_ID_MAPPING.lock().await.get(&message.sender_user_id) {
-                        None => send_dataframe_to_other_service(message,vec_to_send).await,
-                        Some(tx2) => send_dataframe_to_channel(vec_to_send,tx2).await
-                    }
-                }
-            }
+// opening handshake of an HTTP/1.1 connection, then its websocket
+async fn serve_http1_connection<R, W>(mut read_half: R, mut write_half: W, peer_ip: IpAddr, listener: &ListenerConfig, handshake_deadline: Instant, connection_admitted: bool)
+    where R: AsyncRead + Unpin, W: AsyncWrite + Unpin {
+    // frames the client sent right after its request
+    let mut read_buffer = BytesMut::new();
+    let handshake_result = match tcp_handler::read_http_request(&mut read_half, &SERVICE_CONFIG.handshake, handshake_deadline).await {
+        Ok((http_request, bytes_after_request)) => {
+            read_buffer = bytes_after_request;
+            http_handler::handle_http_request(http_request, peer_ip, listener, connection_admitted).await
         }
-        ReadFrom::Channel => send_reply_arrived_to_this_user(vec_to_send,write_half).await
+        Err(e) => Err(e)
     };
-}
 
-async fn send_dataframe_to_other_service(message: Message,vec_to_send: Vec<Vec<u8>> ){
-    if SERVICE_CONFIG.cluster_mode {
-        info!("User not connected to this server");
-        let ip: String = REDIS_CLIENT.lock().await.as_mut().unwrap().get(&message.sender_user_id).unwrap_or_else(|_err| {
-            "".to_string()
-        });
-        info!("User connected to the server: {}",ip);
-
-        if ip.len() > 0 {
-            workers::tcp_message_transmitter::transmit(vec_to_send, ip).await;
-        } else {
-            error!("User Not Connected to any Service")
+    // handshake
+    let (http_resp,handshake_details) = match handshake_result {
+        Ok((http_resp,handshake_details)) => (http_resp,handshake_details),
+        Err(e) if !e.has_response() => {
+            error!("Closing connection without response: {}",e);
+            return;
         }
-    } else {
-        info!("User Not Connected to this Service")
-    }
-}
-
-async fn send_dataframe_to_channel(vec_to_send: Vec<Vec<u8>>, tx2: &Sender<Vec<u8>>){
-    info!("Sending message to channel");
-    let tx2 = tx2.clone();
-    for bytes in vec_to_send {
-        tx2.send(bytes).await.unwrap();
-    }
-    info!("Message Sent");
-}
-
-async fn send_reply_arrived_to_this_user(vec_to_send: Vec<Vec<u8>>, write_half: &mut OwnedWriteHalf ){
-    info!("Sending reply arrived for this user");
-    for vec_data in vec_to_send {
-        write_half.write(&vec_data).await.unwrap();
-    }
-    info!("Reply Sent");
+        Err(e) => {
+            error!("Handshake refused: {}",e);
+            (http_handler::create_error_response(&e),None)
+        }
+    };
+    let http_response_status = http_resp.status().clone();
+    let http_resp_bytes = http_handler::get_http_response_bytes(http_resp);
+    let http_resp_bytes = http_resp_bytes.unwrap();
+    match write_half.write_all(&http_resp_bytes).await {
+        Ok(()) => info!("Data sent size: {}",http_resp_bytes.len()),
+        Err(e) => {
+            error!("Enable to send Data : {}",e);
+            return;
+        }
+    };
+    let handshake_details = match handshake_details {
+        Some(handshake_details) if http_response_status == StatusCode::SWITCHING_PROTOCOLS => handshake_details,
+        _ => {
+            info!("Connection Unsuccessful, Dropping thread");
+            return;
+        }
+    };
+    connection_handler::serve_websocket_connection(read_half, write_half, handshake_details, read_buffer).await;
 }
@@ -0,0 +1,155 @@
+//  The |Origin| header field [RFC6454] is used to protect against
+//    unauthorized cross-origin use of a WebSocket server by scripts using
+//    the WebSocket API in a web browser.  The server is informed of the
+//    script origin generating the WebSocket connection request.  If the
+//    server does not wish to accept connections from this origin, it can
+//    choose to reject the connection by sending an appropriate HTTP error
+//    code.
+//
+// Browsers always send the Origin header, clients without one are only let in
+// when their User-Agent is in the list of non browser clients.
+//
+// This is not a security boundary. It keeps scripts of other sites from using
+// the credentials of a browser, any other client can send whatever Origin and
+// User-Agent it likes. Who may connect is decided by the authenticator.
+
+use http::Request;
+use http::header::{ORIGIN, USER_AGENT};
+use crate::error::http_errors::{ErrorKind, HTTPError, Result};
+use crate::service_config::OriginPolicyConfig;
+
+pub fn check_origin(request: &Request<()>, origin_policy: &OriginPolicyConfig) -> Result<()> {
+    let header_map = request.headers();
+    let mut origins = header_map.get_all(ORIGIN).iter();
+    let origin = match (origins.next(), origins.next()) {
+        (Some(origin), None) => origin,
+        (None, _) => return check_non_browser_client(request, origin_policy),
+        (Some(_), Some(_)) => {
+            crate::error!("Origin rejected, more than one Origin header sent");
+            return Err(HTTPError::new(ErrorKind::Forbidden, "origin not allowed"));
+        }
+    };
+    let origin = match origin.to_str() {
+        Ok(origin) => origin.trim().to_ascii_lowercase(),
+        Err(_) => {
+            crate::error!("Origin rejected, header is not valid ASCII");
+            return Err(HTTPError::new(ErrorKind::Forbidden, "origin not allowed"));
+        }
+    };
+    if origin_policy.allowed_origins.iter().any(|pattern| origin_matches(pattern, &origin)) {
+        crate::info!("Origin allowed: {}",origin);
+        return Ok(());
+    }
+    crate::error!("Origin rejected, {} is not in the allowed origins",origin);
+    Err(HTTPError::new(ErrorKind::Forbidden, "origin not allowed"))
+}
+
+fn check_non_browser_client(request: &Request<()>, origin_policy: &OriginPolicyConfig) -> Result<()> {
+    let user_agent = request.headers().get(USER_AGENT)
+        .and_then(|header_value| header_value.to_str().ok())
+        .unwrap_or("");
+    if origin_policy.non_browser_user_agents.iter().any(|pattern| user_agent_matches(pattern, user_agent)) {
+        crate::info!("No Origin sent, allowed as non browser client: {}",user_agent);
+        return Ok(());
+    }
+    crate::error!("Origin rejected, no Origin sent and User-Agent \"{}\" is not a known non browser client",user_agent);
+    Err(HTTPError::new(ErrorKind::Forbidden, "origin required"))
+}
+
+//  scheme "://" host [ ":" port ]
+//
+// "*" allows every origin, "https://*.cluster23.com" allows any subdomain of
+// cluster23.com but not cluster23.com itself. A pattern without a scheme
+// matches any scheme, a port has to be written out when it is not the default.
+// Origin sent by a sandboxed page is "null", it is only allowed when listed.
+fn origin_matches(pattern: &str, origin: &str) -> bool {
+    if pattern == "*" {
+        return true;
+    }
+    let pattern = pattern.to_ascii_lowercase();
+    let (pattern_scheme, pattern_host) = split_scheme(&pattern);
+    let (origin_scheme, origin_host) = split_scheme(origin);
+    if pattern_scheme.is_some() && pattern_scheme != origin_scheme {
+        return false;
+    }
+    match pattern_host.strip_prefix("*.") {
+        Some(domain) => origin_host.strip_suffix(domain)
+            .and_then(|subdomain| subdomain.strip_suffix('.'))
+            .is_some_and(|subdomain| !subdomain.is_empty()),
+        None => pattern_host == origin_host
+    }
+}
+
+fn split_scheme(origin: &str) -> (Option<&str>, &str) {
+    match origin.split_once("://") {
+        Some((scheme, host)) => (Some(scheme), host),
+        None => (None, origin)
+    }
+}
+
+// "*" allows every client, "pollux-client/*" any version of it
+fn user_agent_matches(pattern: &str, user_agent: &str) -> bool {
+    match pattern.strip_suffix('*') {
+        Some(prefix) => user_agent.starts_with(prefix),
+        None => pattern == user_agent
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(headers: &[(&str, &str)]) -> Request<()> {
+        let mut request_builder = Request::builder();
+        for (name, value) in headers {
+            request_builder = request_builder.header(*name, *value);
+        }
+        request_builder.body(()).unwrap()
+    }
+
+    #[test]
+    fn wildcard_matches_subdomains_only() {
+        let pattern = "https://*.cluster23.com";
+        assert!(origin_matches(pattern, "https://chat.cluster23.com"));
+        assert!(origin_matches(pattern, "https://eu.chat.cluster23.com"));
+        assert!(!origin_matches(pattern, "https://cluster23.com"));
+        assert!(!origin_matches(pattern, "https://.cluster23.com"));
+        // the domain has to match whole labels
+        assert!(!origin_matches(pattern, "https://evilcluster23.com"));
+        assert!(!origin_matches(pattern, "https://chat.cluster23.com.evil.com"));
+        assert!(!origin_matches(pattern, "http://chat.cluster23.com"));
+        assert!(!origin_matches(pattern, "https://chat.cluster23.com:8443"));
+    }
+
+    #[test]
+    fn wildcard_without_scheme_matches_any_scheme() {
+        assert!(origin_matches("*.cluster23.com", "https://chat.cluster23.com"));
+        assert!(origin_matches("*.cluster23.com", "http://chat.cluster23.com"));
+        assert!(origin_matches("*.Cluster23.com:8443", "https://chat.cluster23.com:8443"));
+        assert!(!origin_matches("*.cluster23.com", "null"));
+    }
+
+    #[test]
+    fn origin_is_checked_against_the_allowed_origins() {
+        let origin_policy = OriginPolicyConfig {
+            allowed_origins: vec!["https://cluster23.com".to_string(), "https://*.cluster23.com".to_string()],
+            non_browser_user_agents: vec!["pollux-client/*".to_string()]
+        };
+        assert!(check_origin(&request(&[("origin", "https://Chat.Cluster23.com")]), &origin_policy).is_ok());
+        assert!(check_origin(&request(&[("origin", "https://cluster23.com")]), &origin_policy).is_ok());
+        assert!(check_origin(&request(&[("origin", "https://cluster24.com")]), &origin_policy).is_err());
+        // a non browser User-Agent does not help a request carrying an Origin
+        assert!(check_origin(&request(&[("origin", "null"), ("user-agent", "pollux-client/1.0")]), &origin_policy).is_err());
+        assert!(check_origin(&request(&[("origin", "https://cluster23.com"), ("origin", "https://evil.com")]), &origin_policy).is_err());
+        assert!(check_origin(&request(&[("user-agent", "pollux-client/1.0")]), &origin_policy).is_ok());
+        assert!(check_origin(&request(&[("user-agent", "curl/8.0")]), &origin_policy).is_err());
+    }
+
+    #[test]
+    fn no_origin_is_refused_by_default() {
+        let origin_policy = OriginPolicyConfig::default();
+        assert!(check_origin(&request(&[("origin", "https://anything.example")]), &origin_policy).is_ok());
+        assert!(check_origin(&request(&[]), &origin_policy).is_err());
+        assert!(check_origin(&request(&[("user-agent", "pollux-client/1.0")]), &origin_policy).is_err());
+    }
+}
@@ -0,0 +1,232 @@
+//  To offer use of the PMCE, the client MUST include the
+//    extension name in the "Sec-WebSocket-Extensions" header field of its
+//    opening handshake.
+//
+//         Sec-WebSocket-Extensions: permessage-deflate; client_max_window_bits
+//
+//  The "Per-Message Compressed" bit (RSV1) is set on the first frame of a
+//    compressed message, the payload of all the frames together is the
+//    DEFLATE output with the trailing 0x00 0x00 0xff 0xff removed.
+
+use flate2::{Compress, Compression, Decompress, FlushCompress, FlushDecompress, Status};
+use http::HeaderMap;
+use http::header::SEC_WEBSOCKET_EXTENSIONS;
+use crate::data_frame::DataFrameInfo;
+use crate::error::data_frame_error::{DataFrameError, ErrorKind, Result};
+use crate::service_config::DeflateConfig;
+
+static EXTENSION_NAME: &str = "permessage-deflate";
+static DEFLATE_TRAILER: [u8;4] = [0x00, 0x00, 0xff, 0xff];
+// zlib can't compress with a window smaller than 2^9
+static MIN_SERVER_WINDOW_BITS: u8 = 9;
+static MAX_WINDOW_BITS: u8 = 15;
+
+// parameters accepted from the client offer, these are sent back in the handshake response
+#[derive(Debug, Clone)]
+pub struct DeflateParams {
+    pub server_no_context_takeover: bool,
+    pub client_no_context_takeover: bool,
+    pub server_max_window_bits: Option<u8>,
+    pub client_max_window_bits: Option<u8>
+}
+
+impl DeflateParams {
+    pub fn response_header_value(&self) -> String {
+        let mut header_value = EXTENSION_NAME.to_string();
+        if self.server_no_context_takeover {
+            header_value.push_str("; server_no_context_takeover");
+        }
+        if self.client_no_context_takeover {
+            header_value.push_str("; client_no_context_takeover");
+        }
+        if let Some(window_bits) = self.server_max_window_bits {
+            header_value.push_str(&format!("; server_max_window_bits={}", window_bits));
+        }
+        if let Some(window_bits) = self.client_max_window_bits {
+            header_value.push_str(&format!("; client_max_window_bits={}", window_bits));
+        }
+        header_value
+    }
+}
+
+//  The server accepts an offer by including an element for it in the response,
+//    offers are tried in the order the client sent them and the first one the
+//    server is able to honor is accepted
+pub fn negotiate(header_map: &HeaderMap, deflate_config: &DeflateConfig) -> Option<DeflateParams> {
+    if !deflate_config.enabled {
+        return None;
+    }
+    for header_value in header_map.get_all(SEC_WEBSOCKET_EXTENSIONS) {
+        let header_value = match header_value.to_str() {
+            Ok(header_value) => header_value,
+            Err(_) => continue
+        };
+        for offer in header_value.split(',') {
+            let mut offer_params = offer.split(';').map(|param| param.trim());
+            if offer_params.next() != Some(EXTENSION_NAME) {
+                continue;
+            }
+            match parse_offer_params(offer_params, deflate_config) {
+                Ok(deflate_params) => {
+                    crate::info!("permessage-deflate negotiated: {:?}",deflate_params);
+                    return Some(deflate_params);
+                }
+                Err(e) => crate::info!("Declining permessage-deflate offer: {}",e)
+            }
+        }
+    }
+    None
+}
+
+fn parse_offer_params<'a>(offer_params: impl Iterator<Item=&'a str>, deflate_config: &DeflateConfig) -> std::result::Result<DeflateParams, &'static str> {
+    let mut deflate_params = DeflateParams {
+        server_no_context_takeover: false,
+        client_no_context_takeover: false,
+        server_max_window_bits: None,
+        client_max_window_bits: None
+    };
+    let mut client_max_window_bits_offered = false;
+
+    for param in offer_params {
+        let (name, value) = match param.split_once('=') {
+            Some((name, value)) => (name.trim(), Some(value.trim().trim_matches('"'))),
+            None => (param, None)
+        };
+        match (name, value) {
+            ("server_no_context_takeover", None) if !deflate_params.server_no_context_takeover => {
+                deflate_params.server_no_context_takeover = true;
+            }
+            ("client_no_context_takeover", None) if !deflate_params.client_no_context_takeover => {
+                deflate_params.client_no_context_takeover = true;
+            }
+            ("server_max_window_bits", Some(value)) if deflate_params.server_max_window_bits.is_none() => {
+                let window_bits = parse_window_bits(value)?;
+                if window_bits < MIN_SERVER_WINDOW_BITS {
+                    return Err("server_max_window_bits smaller than 9 is not supported");
+                }
+                deflate_params.server_max_window_bits = Some(window_bits);
+            }
+            ("client_max_window_bits", value) if !client_max_window_bits_offered => {
+                client_max_window_bits_offered = true;
+                if let Some(value) = value {
+                    deflate_params.client_max_window_bits = Some(parse_window_bits(value)?);
+                }
+            }
+            _ => return Err("unknown or duplicate extension parameter")
+        }
+    }
+
+    // the server can always ask for no context takeover even if the client did not
+    deflate_params.server_no_context_takeover |= deflate_config.server_no_context_takeover;
+    deflate_params.client_no_context_takeover |= deflate_config.client_no_context_takeover;
+    Ok(deflate_params)
+}
+
+fn parse_window_bits(value: &str) -> std::result::Result<u8, &'static str> {
+    match value.parse::<u8>() {
+        Ok(window_bits) if (8..=MAX_WINDOW_BITS).contains(&window_bits) => Ok(window_bits),
+        _ => Err("invalid window bits value")
+    }
+}
+
+// compression state of one connection
+pub struct DeflateContext {
+    compressor: Compress,
+    decompressor: Decompress,
+    server_no_context_takeover: bool,
+    client_no_context_takeover: bool,
+    compression_threshold: usize
+}
+
+impl DeflateContext {
+    pub fn new(deflate_params: &DeflateParams, deflate_config: &DeflateConfig) -> DeflateContext {
+        let server_window_bits = deflate_params.server_max_window_bits.unwrap_or(MAX_WINDOW_BITS);
+        DeflateContext {
+            compressor: Compress::new_with_window_bits(Compression::default(), false, server_window_bits),
+            // a window of 2^15 can inflate data compressed with any smaller window
+            decompressor: Decompress::new(false),
+            server_no_context_takeover: deflate_params.server_no_context_takeover,
+            client_no_context_takeover: deflate_params.client_no_context_takeover,
+            compression_threshold: deflate_config.compression_threshold
+        }
+    }
+
+    pub fn should_compress(&self, data_len: usize) -> bool {
+        data_len > self.compression_threshold
+    }
+
+    // inflates the payload of a complete message which had RSV1 set on its first frame
+    pub fn decompress_message(&mut self, data_frame: &mut DataFrameInfo, max_message_size: usize) -> Result<()> {
+        let mut input = std::mem::take(&mut data_frame.payload_data);
+        input.extend_from_slice(&DEFLATE_TRAILER);
+
+        let mut output: Vec<u8> = Vec::with_capacity(input.len() * 2);
+        let mut consumed: usize = 0;
+        loop {
+            if output.len() == output.capacity() {
+                output.reserve(input.len().max(1024));
+            }
+            let total_in = self.decompressor.total_in();
+            let status = self.decompressor.decompress_vec(&input[consumed..], &mut output, FlushDecompress::Sync)
+                .map_err(|e| {
+                    crate::error!("Not able to inflate message: {}",e);
+                    DataFrameError::new(ErrorKind::InvalidPayload, "not able to inflate message")
+                })?;
+            consumed += (self.decompressor.total_in() - total_in) as usize;
+
+            if output.len() > max_message_size {
+                return Err(DataFrameError::new(ErrorKind::MessageTooBig, "inflated message is too big"));
+            }
+            if status == Status::StreamEnd || (consumed == input.len() && output.len() < output.capacity()) {
+                break;
+            }
+        }
+
+        if self.client_no_context_takeover {
+            self.decompressor.reset(false);
+        }
+        data_frame.payload_length = output.len();
+        data_frame.payload_data = output;
+        data_frame.rs1_bit_set = false;
+        Ok(())
+    }
+
+    pub fn compress_message(&mut self, data: &[u8]) -> Result<Vec<u8>> {
+        let mut output: Vec<u8> = Vec::with_capacity(data.len() / 2 + 64);
+        let mut consumed: usize = 0;
+        loop {
+            if output.capacity() - output.len() < 64 {
+                output.reserve(data.len() / 2 + 64);
+            }
+            let total_in = self.compressor.total_in();
+            self.compressor.compress_vec(&data[consumed..], &mut output, FlushCompress::Sync)
+                .map_err(|e| {
+                    crate::error!("Not able to deflate message: {}",e);
+                    DataFrameError::new(ErrorKind::UnknownError, "not able to deflate message")
+                })?;
+            consumed += (self.compressor.total_in() - total_in) as usize;
+            // sync flush is complete once the compressor stops filling the whole output buffer
+            if consumed == data.len() && output.len() < output.capacity() {
+                break;
+            }
+        }
+
+        if output.ends_with(&DEFLATE_TRAILER) {
+            output.truncate(output.len() - DEFLATE_TRAILER.len());
+        }
+        if self.server_no_context_takeover {
+            self.compressor.reset();
+        }
+        Ok(output)
+    }
+
+    // replaces the payload of an outgoing message with its compressed form
+    pub fn compress_dataframe(&mut self, data_frame: &mut DataFrameInfo) -> Result<()> {
+        let compressed_data = self.compress_message(&data_frame.payload_data)?;
+        crate::info!("Message compressed from {} to {} bytes",data_frame.payload_data.len(),compressed_data.len());
+        data_frame.payload_length = compressed_data.len();
+        data_frame.payload_data = compressed_data;
+        data_frame.rs1_bit_set = true;
+        Ok(())
+    }
+}
@@ -1,5 +1,8 @@
+use std::time::Duration;
 use redis::{Connection, RedisResult};
 
+static REDIS_URL: &str = "redis_client://13.232.89.31/";
+
 pub struct RedisClient {
     pub redis_connection: Connection
 }
@@ -7,7 +10,7 @@ pub struct RedisClient {
 impl RedisClient {
     // redis_client-server --protected-mode no
     pub async fn initialize_redis_connection() -> RedisResult<RedisClient>{
-        let client = redis::Client::open("redis_client://13.232.89.31/")?;
+        let client = redis::Client::open(REDIS_URL)?;
         Ok(RedisClient{
             redis_connection: client.get_connection()?
         })
@@ -25,4 +28,14 @@ impl RedisClient {
         redis::cmd("DEL").arg(key).query::<()>(&mut self.redis_connection)
     }
 
+    // on a connection of its own, a reply arriving after the timeout can not be
+    // taken for the reply to a command of the shared connection
+    pub fn ping(timeout: Duration) -> RedisResult<String> {
+        let client = redis::Client::open(REDIS_URL)?;
+        let mut redis_connection = client.get_connection_with_timeout(timeout)?;
+        redis_connection.set_read_timeout(Some(timeout))?;
+        redis_connection.set_write_timeout(Some(timeout))?;
+        redis::cmd("PING").query(&mut redis_connection)
+    }
+
 }
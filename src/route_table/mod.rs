@@ -0,0 +1,231 @@
+//  The "Request-URI" of the GET method [RFC2616] is used to
+//    identify the endpoint of the WebSocket connection, both to allow
+//    multiple domains to be served from one IP address and to allow
+//    multiple WebSocket endpoints to be served by a single server.
+//
+// Routes come from the config file. Each virtual host has a route set
+// of its own, picked by the Host header of the request. A listener with routes
+// of its own serves only those, the others serve the top level and virtual
+// host ones.
+
+use std::collections::HashMap;
+use http::Uri;
+use percent_encoding::percent_decode_str;
+use crate::service_config::{ListenerConfig, RouteConfig, SizeLimits, VirtualHostConfig};
+
+lazy_static! {
+    // top level routes are the last entry, they are used when no virtual host matches
+    static ref VIRTUAL_HOSTS: Vec<VirtualHostConfig> = {
+        let mut virtual_hosts = crate::SERVICE_CONFIG.virtual_hosts.clone();
+        virtual_hosts.push(VirtualHostConfig {
+            host_names: crate::SERVICE_CONFIG.host_names.clone(),
+            routes: crate::SERVICE_CONFIG.routes.clone()
+        });
+        virtual_hosts
+    };
+    // by listener name, only for listeners with routes of their own
+    static ref LISTENER_VIRTUAL_HOSTS: HashMap<String, Vec<VirtualHostConfig>> = {
+        crate::listener::LISTENERS.iter()
+            .filter_map(|listener| {
+                let routes = listener.routes.clone()?;
+                Some((listener.name.clone(), vec![VirtualHostConfig {
+                    host_names: listener.host_names.clone(),
+                    routes
+                }]))
+            })
+            .collect()
+    };
+}
+
+// route a request was matched to along with the values taken from its uri
+#[derive(Debug, Clone)]
+pub struct RouteMatch {
+    pub route: RouteConfig,
+    pub path_params: HashMap<String, String>,
+    pub query_params: HashMap<String, String>,
+    // limits of the listener the request came on, for a route without any
+    pub default_size_limits: SizeLimits
+}
+
+impl RouteMatch {
+    pub fn size_limits(&self) -> SizeLimits {
+        self.route.size_limits.unwrap_or(self.default_size_limits)
+    }
+}
+
+// virtual hosts served on a listener
+fn virtual_hosts(listener: &ListenerConfig) -> &'static [VirtualHostConfig] {
+    match LISTENER_VIRTUAL_HOSTS.get(&listener.name) {
+        Some(virtual_hosts) => virtual_hosts,
+        None => &VIRTUAL_HOSTS
+    }
+}
+
+pub fn is_known_host(listener: &ListenerConfig, host: &str) -> bool {
+    virtual_hosts(listener).iter().any(|virtual_host| host_matches(&virtual_host.host_names, host))
+}
+
+// routes of the first virtual host serving the host are tried in the order
+// they were declared, first one matching wins
+pub fn match_route(listener: &ListenerConfig, host: &str, uri: &Uri) -> Option<RouteMatch> {
+    let virtual_host = virtual_hosts(listener).iter()
+        .find(|virtual_host| host_matches(&virtual_host.host_names, host))?;
+    for route in virtual_host.routes.iter() {
+        if let Some(path_params) = match_path(&route.path, uri.path()) {
+            return Some(RouteMatch {
+                route: route.clone(),
+                path_params,
+                query_params: parse_query(uri.query()),
+                default_size_limits: listener.size_limits.unwrap_or(crate::SERVICE_CONFIG.size_limits)
+            });
+        }
+    }
+    None
+}
+
+// messages forwarded by other services can be for any route of any listener
+pub fn largest_message_size() -> usize {
+    let route_limits = VIRTUAL_HOSTS.iter()
+        .chain(LISTENER_VIRTUAL_HOSTS.values().flatten())
+        .flat_map(|virtual_host| virtual_host.routes.iter())
+        .filter_map(|route| route.size_limits);
+    let listener_limits = crate::listener::LISTENERS.iter()
+        .filter_map(|listener| listener.size_limits);
+    route_limits.chain(listener_limits)
+        .map(|size_limits| size_limits.max_message_size)
+        .fold(crate::SERVICE_CONFIG.size_limits.max_message_size, usize::max)
+}
+
+//  Host = uri-host [ ":" port ]
+//
+// host names are case-insensitive, one configured without a port matches any port
+fn host_matches(host_names: &[String], host: &str) -> bool {
+    let (name, port) = split_port(host);
+    host_names.iter().any(|host_name| {
+        if host_name == "*" {
+            return true;
+        }
+        match split_port(host_name) {
+            (host_name, None) => host_name.eq_ignore_ascii_case(name),
+            (host_name, host_port) => host_name.eq_ignore_ascii_case(name) && host_port == port
+        }
+    })
+}
+
+// an IPv6 address is written in brackets, "[::1]:3999"
+fn split_port(host: &str) -> (&str, Option<&str>) {
+    let port_start = match host.rfind(']') {
+        Some(bracket_end) => bracket_end + 1,
+        None => 0
+    };
+    match host[port_start..].rfind(':') {
+        Some(colon) => (&host[..port_start + colon], Some(&host[port_start + colon + 1..])),
+        None => (host, None)
+    }
+}
+
+// segments are compared case-sensitively, a parameter takes a whole segment
+fn match_path(route_path: &str, request_path: &str) -> Option<HashMap<String, String>> {
+    let mut path_params = HashMap::new();
+    let mut route_segments = route_path.trim_end_matches('/').split('/');
+    let mut request_segments = request_path.trim_end_matches('/').split('/');
+    loop {
+        match (route_segments.next(), request_segments.next()) {
+            (None, None) => return Some(path_params),
+            (Some(route_segment), Some(request_segment)) => {
+                if let Some(param_name) = route_segment.strip_prefix('{').and_then(|segment| segment.strip_suffix('}')) {
+                    if request_segment.is_empty() {
+                        return None;
+                    }
+                    let param_value = percent_decode_str(request_segment).decode_utf8().ok()?;
+                    path_params.insert(param_name.to_string(), param_value.to_string());
+                } else if route_segment != request_segment {
+                    return None;
+                }
+            }
+            _ => return None
+        }
+    }
+}
+
+// a parameter given more than once keeps its last value
+fn parse_query(query: Option<&str>) -> HashMap<String, String> {
+    match query {
+        Some(query) => form_urlencoded::parse(query.as_bytes()).into_owned().collect(),
+        None => HashMap::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn host_names(host_names: &[&str]) -> Vec<String> {
+        host_names.iter().map(|host_name| host_name.to_string()).collect()
+    }
+
+    #[test]
+    fn host_name_with_and_without_port() {
+        let any_port = host_names(&["chat.cluster23.com"]);
+        assert!(host_matches(&any_port, "chat.cluster23.com"));
+        assert!(host_matches(&any_port, "Chat.Cluster23.COM:8443"));
+        assert!(!host_matches(&any_port, "cluster23.com"));
+        assert!(!host_matches(&any_port, "chat.cluster23.com.evil.com"));
+
+        let one_port = host_names(&["chat.cluster23.com:8443"]);
+        assert!(host_matches(&one_port, "chat.cluster23.com:8443"));
+        assert!(!host_matches(&one_port, "chat.cluster23.com:9443"));
+    }
+
+    #[test]
+    fn default_port_is_not_written_out() {
+        //  an explicit ":" port, where the port is empty or the default port
+        //    for the scheme, is equivalent to one where the port and its ":"
+        //    delimiter are elided
+        let default_port = host_names(&["chat.cluster23.com:443"]);
+        assert!(host_matches(&default_port, "chat.cluster23.com:443"));
+        // which port a Host without one means depends on the scheme, list the
+        // name without a port to serve it
+        assert!(!host_matches(&default_port, "chat.cluster23.com"));
+    }
+
+    #[test]
+    fn ipv6_literals() {
+        let any_port = host_names(&["[::1]"]);
+        assert!(host_matches(&any_port, "[::1]"));
+        assert!(host_matches(&any_port, "[::1]:3999"));
+        assert!(!host_matches(&any_port, "[::2]:3999"));
+
+        let one_port = host_names(&["[2001:db8::1]:3999"]);
+        assert!(host_matches(&one_port, "[2001:DB8::1]:3999"));
+        assert!(!host_matches(&one_port, "[2001:db8::1]"));
+        assert!(!host_matches(&one_port, "[2001:db8::1]:4000"));
+        assert_eq!(split_port("[2001:db8::1]:3999"), ("[2001:db8::1]", Some("3999")));
+        assert_eq!(split_port("[2001:db8::1]"), ("[2001:db8::1]", None));
+    }
+
+    #[test]
+    fn wildcard_host_matches_everything() {
+        let wildcard = host_names(&["chat.cluster23.com", "*"]);
+        assert!(host_matches(&wildcard, "cluster24.com"));
+        assert!(host_matches(&wildcard, "[::1]:3999"));
+        assert!(host_matches(&wildcard, ""));
+        assert!(!host_matches(&host_names(&[]), "chat.cluster23.com"));
+    }
+
+    #[test]
+    fn path_segments_are_case_sensitive() {
+        assert!(match_path("/chat", "/chat").is_some());
+        assert!(match_path("/chat", "/chat/").is_some());
+        assert!(match_path("/chat", "/Chat").is_none());
+        assert!(match_path("/chat", "/chat/more").is_none());
+    }
+
+    #[test]
+    fn path_params_are_decoded() {
+        let path_params = match_path("/rooms/{room_id}", "/rooms/a%20b").unwrap();
+        assert_eq!(path_params.get("room_id").map(String::as_str), Some("a b"));
+        assert!(match_path("/rooms/{room_id}", "/Rooms/a").is_none());
+        assert!(match_path("/rooms/{room_id}", "/rooms/").is_none());
+    }
+}
@@ -4,7 +4,335 @@ use serde::{Deserialize,Serialize};
 #[derive(Deserialize,Serialize,Debug)]
 pub struct ServiceConfig {
     pub cluster_mode: bool,
-    pub websocket_port: String
+    pub websocket_port: String,
+    #[serde(default)]
+    pub permessage_deflate: DeflateConfig,
+    #[serde(default)]
+    pub heartbeat: HeartbeatConfig,
+    #[serde(default)]
+    pub handshake: HandshakeConfig,
+    #[serde(default)]
+    pub timeouts: TimeoutConfig,
+    #[serde(default)]
+    pub tcp: TcpConfig,
+    #[serde(default)]
+    pub admission: AdmissionConfig,
+    #[serde(default)]
+    pub size_limits: SizeLimits,
+    // Host header values the top level routes are served for
+    #[serde(default = "default_host_names")]
+    pub host_names: Vec<String>,
+    #[serde(default = "default_routes")]
+    pub routes: Vec<RouteConfig>,
+    // host names with a route set of their own, tried before the top level one
+    #[serde(default)]
+    pub virtual_hosts: Vec<VirtualHostConfig>,
+    #[serde(default)]
+    pub authentication: AuthenticationConfig,
+    #[serde(default)]
+    pub origin_policy: OriginPolicyConfig,
+    #[serde(default)]
+    pub http2: Http2Config,
+    #[serde(default)]
+    pub trusted_proxies: TrustedProxyConfig,
+    #[serde(default)]
+    pub tls: TlsConfig,
+    #[serde(default)]
+    pub cluster_tls: ClusterTlsConfig,
+    // without any listener connections are accepted on one TCP address
+    #[serde(default)]
+    pub listeners: Vec<ListenerConfig>
+}
+
+// a socket connections are accepted on, every listener runs the same
+// connection pipeline with its own settings
+//
+//         { "name": "public", "address": "[::]:443", "tls": true },
+//         { "name": "admin", "address": "127.0.0.1:9000", "routes": [ ... ] },
+//         { "name": "nginx", "address": "unix:/run/pollux/pollux.sock" }
+#[derive(Deserialize,Serialize,Debug,Clone)]
+pub struct ListenerConfig {
+    pub name: String,
+    // "host:port", or "unix:" followed by the path of the socket file
+    pub address: String,
+    // an IPv6 wildcard address accepts IPv4 clients too unless this is off
+    #[serde(default = "default_dual_stack")]
+    pub dual_stack: bool,
+    // serves wss:// with the certificates of the tls section
+    #[serde(default)]
+    pub tls: bool,
+    // route set of its own instead of the top level and virtual host ones,
+    // served for host_names
+    #[serde(default)]
+    pub routes: Option<Vec<RouteConfig>>,
+    #[serde(default = "default_host_names")]
+    pub host_names: Vec<String>,
+    // limits of the routes served on the listener which have none of their own
+    #[serde(default)]
+    pub size_limits: Option<SizeLimits>
+}
+
+fn default_dual_stack() -> bool {
+    true
+}
+
+// TLS on the links between the services of a cluster. Both ends present a
+// certificate signed by the cluster CA and issued for node_name, a peer with
+// any other certificate is refused.
+//
+//         { "enabled": true, "ca_file": "./tls/cluster-ca.crt", "cert_file": "./tls/node.crt",
+//           "key_file": "./tls/node.key", "node_name": "node.pollux.cluster23.internal" }
+#[derive(Deserialize,Serialize,Debug,Default)]
+pub struct ClusterTlsConfig {
+    pub enabled: bool,
+    #[serde(default)]
+    pub ca_file: String,
+    #[serde(default)]
+    pub cert_file: String,
+    #[serde(default)]
+    pub key_file: String,
+    // DNS name in the certificate of every node, services are reached by IP
+    #[serde(default)]
+    pub node_name: String
+}
+
+// wss:// served by the service itself. The first certificate is used for
+// clients which send no SNI or a name none of the certificates is for.
+//
+//         { "enabled": true,
+//           "certificates": [{ "server_names": ["chat.cluster23.com"],
+//                              "cert_file": "./tls/chat.crt", "key_file": "./tls/chat.key" }] }
+#[derive(Deserialize,Serialize,Debug)]
+pub struct TlsConfig {
+    pub enabled: bool,
+    #[serde(default)]
+    pub certificates: Vec<CertificateConfig>,
+    // offered in this order, "h2" only when http2 is enabled
+    #[serde(default = "default_alpn_protocols")]
+    pub alpn_protocols: Vec<String>,
+    // certificate and key files are checked for changes this often, 0 turns
+    // reloading off
+    #[serde(default = "default_reload_interval_secs")]
+    pub reload_interval_secs: u64
+}
+
+impl Default for TlsConfig {
+    fn default() -> Self {
+        TlsConfig {
+            enabled: false,
+            certificates: Vec::new(),
+            alpn_protocols: default_alpn_protocols(),
+            reload_interval_secs: default_reload_interval_secs()
+        }
+    }
+}
+
+fn default_alpn_protocols() -> Vec<String> {
+    vec!["h2".to_string(), "http/1.1".to_string()]
+}
+
+fn default_reload_interval_secs() -> u64 {
+    60
+}
+
+// PEM files, the certificate file holds the whole chain. A server name can
+// start with "*." to cover the subdomains of a domain.
+#[derive(Deserialize,Serialize,Debug)]
+pub struct CertificateConfig {
+    pub server_names: Vec<String>,
+    pub cert_file: String,
+    pub key_file: String
+}
+
+// load balancers and proxies in front of the service. The client address they
+// pass on, in a PROXY protocol header or in Forwarded/X-Forwarded-For, is only
+// believed when the connection comes from one of these networks or from a
+// Unix domain socket listener
+//
+//         { "proxy_protocol": true, "cidrs": ["10.0.0.0/8", "fd00::/8"] }
+#[derive(Deserialize,Serialize,Debug,Default)]
+pub struct TrustedProxyConfig {
+    // connections from a trusted proxy start with a PROXY protocol v1 or v2 header
+    #[serde(default)]
+    pub proxy_protocol: bool,
+    #[serde(default)]
+    pub cidrs: Vec<String>
+}
+
+// websockets over HTTP/2 (RFC 8441), a connection starting with the HTTP/2
+// preface is served as HTTP/2 instead of HTTP/1.1
+#[derive(Deserialize,Serialize,Debug)]
+pub struct Http2Config {
+    pub enabled: bool,
+    // websockets one HTTP/2 connection can carry at the same time
+    pub max_concurrent_streams: u32
+}
+
+impl Default for Http2Config {
+    fn default() -> Self {
+        Http2Config {
+            enabled: false,
+            max_concurrent_streams: 100
+        }
+    }
+}
+
+// origins browsers may open a connection from, any origin is allowed when the
+// section is left out but a client sending no Origin is not
+//
+//         { "allowed_origins": ["https://cluster23.com", "https://*.cluster23.com"],
+//           "non_browser_user_agents": ["pollux-client/*"] }
+#[derive(Deserialize,Serialize,Debug)]
+pub struct OriginPolicyConfig {
+    pub allowed_origins: Vec<String>,
+    // clients sending no Origin header are let in when their User-Agent matches
+    #[serde(default)]
+    pub non_browser_user_agents: Vec<String>
+}
+
+impl Default for OriginPolicyConfig {
+    fn default() -> Self {
+        OriginPolicyConfig {
+            allowed_origins: vec!["*".to_string()],
+            non_browser_user_agents: Vec::new()
+        }
+    }
+}
+
+// how the user opening a connection is identified during the handshake
+//
+//         { "method": "jwt", "algorithm": "RS256", "key_file": "./keys/jwt.pub.pem" }
+#[derive(Deserialize,Serialize,Debug,Default)]
+#[serde(tag = "method", rename_all = "snake_case")]
+pub enum AuthenticationConfig {
+    // trusts the user-id header sent by the client, only for clients on a trusted network
+    #[default]
+    UserIdHeader,
+    Jwt(JwtConfig)
+}
+
+#[derive(Deserialize,Serialize,Debug)]
+pub struct JwtConfig {
+    // HS256 or RS256
+    pub algorithm: jsonwebtoken::Algorithm,
+    // shared secret for HS256, PEM encoded public key for RS256
+    pub key_file: String,
+    // token must be issued for one of these, not checked if empty
+    #[serde(default)]
+    pub audience: Vec<String>,
+    #[serde(default)]
+    pub issuer: Option<String>,
+    // claim holding the user id
+    #[serde(default = "default_user_id_claim")]
+    pub user_id_claim: String,
+    // token is looked for in the Authorization header, then this query parameter, then the cookie
+    #[serde(default = "default_token_query_param")]
+    pub token_query_param: String,
+    #[serde(default)]
+    pub token_cookie: Option<String>,
+    // allowed clock difference when checking exp and nbf
+    #[serde(default = "default_leeway_secs")]
+    pub leeway_secs: u64
+}
+
+fn default_user_id_claim() -> String {
+    "sub".to_string()
+}
+
+fn default_token_query_param() -> String {
+    "token".to_string()
+}
+
+fn default_leeway_secs() -> u64 {
+    60
+}
+
+// paths a websocket connection can be opened on, a path segment written as
+// {name} matches any value and is passed to the connection as a path parameter
+//
+//         { "path": "/rooms/{room_id}", "handler": "chat" }
+#[derive(Deserialize,Serialize,Debug,Clone)]
+pub struct RouteConfig {
+    pub path: String,
+    #[serde(default)]
+    pub handler: HandlerKind,
+    // names of registered subprotocols the route accepts, the client's order of
+    // preference decides between them
+    #[serde(default)]
+    pub sub_protocols: Vec<String>,
+    // overrides size_limits, e.g. for an endpoint sharing files
+    #[serde(default)]
+    pub size_limits: Option<SizeLimits>,
+    // big binary frames read without buffering their payload, only echo
+    // routes stream
+    #[serde(default)]
+    pub streaming: Option<StreamingConfig>
+}
+
+// a final, uncompressed binary frame of at least min_frame_size is streamed,
+// it can be up to max_frame_size instead of the frame size limit
+#[derive(Deserialize,Serialize,Debug,Clone,Copy)]
+pub struct StreamingConfig {
+    pub min_frame_size: usize,
+    pub max_frame_size: usize
+}
+
+// one deployment serving more than one domain
+//
+//         { "host_names": ["ws.partner.example.com"], "routes": [ ... ] }
+//
+// a host name without a port matches any port, "*" matches any host
+#[derive(Deserialize,Serialize,Debug,Clone)]
+pub struct VirtualHostConfig {
+    pub host_names: Vec<String>,
+    pub routes: Vec<RouteConfig>
+}
+
+// what is done with the messages received on a route
+#[derive(Deserialize,Serialize,Debug,Clone,Copy,PartialEq,Default)]
+#[serde(rename_all = "snake_case")]
+pub enum HandlerKind {
+    // message is delivered to the user named in it
+    #[default]
+    Chat,
+    // message is sent back as it is
+    Echo
+}
+
+fn default_host_names() -> Vec<String> {
+    vec!["*".to_string()]
+}
+
+fn default_routes() -> Vec<RouteConfig> {
+    vec![RouteConfig {
+        path: "/chat".to_string(),
+        handler: HandlerKind::Chat,
+        sub_protocols: vec!["v1.chat.cluster23.com".to_string()],
+        size_limits: None,
+        streaming: None
+    }]
+}
+
+// settings for the permessage-deflate extension (RFC 7692)
+#[derive(Deserialize,Serialize,Debug)]
+pub struct DeflateConfig {
+    pub enabled: bool,
+    // messages smaller than this many bytes are sent uncompressed
+    pub compression_threshold: usize,
+    // ask for the context to be reset after every message, saves memory per connection
+    pub server_no_context_takeover: bool,
+    pub client_no_context_takeover: bool
+}
+
+impl Default for DeflateConfig {
+    fn default() -> Self {
+        DeflateConfig {
+            enabled: true,
+            compression_threshold: 256,
+            server_no_context_takeover: false,
+            client_no_context_takeover: false
+        }
+    }
 }
 
 #[derive(Deserialize,Serialize,Debug)]
@@ -13,6 +341,138 @@ pub struct GlobalConfig {
     pub prod: ServiceConfig
 }
 
+#[derive(Deserialize,Serialize,Debug)]
+pub struct HeartbeatConfig {
+    // 0 disables the ping from server
+    pub ping_interval_secs: u64,
+    // connection is closed if the Pong does not arrive in this time
+    pub pong_timeout_secs: u64
+}
+
+impl Default for HeartbeatConfig {
+    fn default() -> Self {
+        HeartbeatConfig {
+            ping_interval_secs: 30,
+            pong_timeout_secs: 10
+        }
+    }
+}
+
+// limits on the opening handshake request
+#[derive(Deserialize,Serialize,Debug)]
+pub struct HandshakeConfig {
+    // request line and headers, a bigger request gets a 431
+    pub max_header_size: usize,
+    // whole request has to arrive in this time, otherwise it gets a 408
+    pub timeout_secs: u64
+}
+
+impl Default for HandshakeConfig {
+    fn default() -> Self {
+        HandshakeConfig {
+            max_header_size: 8192,
+            timeout_secs: 10
+        }
+    }
+}
+
+// deadlines once the websocket is open, 0 disables one. The handshake has its
+// own in the handshake section.
+#[derive(Deserialize,Serialize,Debug)]
+pub struct TimeoutConfig {
+    // a frame has to be complete this long after its first bytes arrived,
+    // otherwise the connection is closed with 1008 (policy violation)
+    pub frame_timeout_secs: u64,
+    // connection is closed with 1001 (going away) when no message was sent or
+    // received for this long, pings and pongs do not count
+    pub idle_timeout_secs: u64
+}
+
+impl Default for TimeoutConfig {
+    fn default() -> Self {
+        TimeoutConfig {
+            frame_timeout_secs: 30,
+            idle_timeout_secs: 600
+        }
+    }
+}
+
+// options set on every accepted TCP connection
+#[derive(Deserialize,Serialize,Debug)]
+pub struct TcpConfig {
+    // small frames are sent right away instead of waiting to be coalesced
+    pub nodelay: bool,
+    // idle time before the kernel starts probing the peer, 0 disables keepalive
+    pub keepalive_secs: u64
+}
+
+impl Default for TcpConfig {
+    fn default() -> Self {
+        TcpConfig {
+            nodelay: true,
+            keepalive_secs: 60
+        }
+    }
+}
+
+// limits on what is open at the same time, 0 is no limit
+#[derive(Deserialize,Serialize,Debug)]
+pub struct AdmissionConfig {
+    // connections counted from accept, still in their handshake or not. An
+    // HTTP/2 connection counts once whatever the streams it carries.
+    pub max_connections: usize,
+    // websockets by client address, behind any trusted proxy. Every stream of
+    // an HTTP/2 connection counts as a websocket.
+    pub max_connections_per_ip: usize,
+    pub when_limited: LimitAction,
+    // a failed accept, e.g. out of file descriptors, is tried again after a
+    // pause doubled on every failure in a row up to the max
+    pub accept_backoff_initial_ms: u64,
+    pub accept_backoff_max_ms: u64
+}
+
+impl Default for AdmissionConfig {
+    fn default() -> Self {
+        AdmissionConfig {
+            max_connections: 0,
+            max_connections_per_ip: 0,
+            when_limited: LimitAction::ServiceUnavailable,
+            accept_backoff_initial_ms: 5,
+            accept_backoff_max_ms: 1000
+        }
+    }
+}
+
+#[derive(Deserialize,Serialize,Debug,Clone,Copy,PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum LimitAction {
+    // handshake is refused with a 503, a connection over the connection cap
+    // is kept until its request is answered
+    ServiceUnavailable,
+    // connection is closed without a response, over the connection cap it is
+    // closed as soon as it is accepted
+    Close
+}
+
+// checked before anything is allocated for the payload, a peer going over
+// them gets the connection closed with 1009 (message too big)
+#[derive(Deserialize,Serialize,Debug,Clone,Copy)]
+pub struct SizeLimits {
+    // payload of a single frame
+    pub max_frame_size: usize,
+    // payload of a whole message, after reassembling fragments and inflating
+    pub max_message_size: usize
+}
+
+impl Default for SizeLimits {
+    fn default() -> Self {
+        SizeLimits {
+            max_frame_size: 1024 * 1024,
+            max_message_size: 16 * 1024 * 1024
+        }
+    }
+}
+
 pub fn new_config(env: String) -> ServiceConfig{
     let data = fs::read_to_string("./config.json")
         .expect("Unable to read file");
@@ -30,6 +490,23 @@ pub fn new_config(env: String) -> ServiceConfig{
 pub fn get_default_config() -> ServiceConfig {
     ServiceConfig {
         cluster_mode: false,
-        websocket_port: "3999".to_owned()
+        websocket_port: "3999".to_owned(),
+        permessage_deflate: DeflateConfig::default(),
+        heartbeat: HeartbeatConfig::default(),
+        handshake: HandshakeConfig::default(),
+        timeouts: TimeoutConfig::default(),
+        tcp: TcpConfig::default(),
+        admission: AdmissionConfig::default(),
+        size_limits: SizeLimits::default(),
+        host_names: default_host_names(),
+        routes: default_routes(),
+        virtual_hosts: Vec::new(),
+        authentication: AuthenticationConfig::default(),
+        origin_policy: OriginPolicyConfig::default(),
+        http2: Http2Config::default(),
+        trusted_proxies: TrustedProxyConfig::default(),
+        tls: TlsConfig::default(),
+        cluster_tls: ClusterTlsConfig::default(),
+        listeners: Vec::new()
     }
 }
\ No newline at end of file
@@ -0,0 +1,199 @@
+//  |Sec-WebSocket-Protocol|
+//       Optionally, a |Sec-WebSocket-Protocol| header field, with a list
+//       of values indicating which protocols the client would like to
+//       speak, ordered by preference.
+//
+// Subprotocols are built in, each decides which handler serves
+// the messages of a connection and the codec reading and writing them. A
+// route lists the subprotocols it accepts by name.
+//
+// Messages travel between connections, and between services, in the v1 format.
+// A connection speaking another subprotocol converts them with its codec.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use http::HeaderMap;
+use http::header::SEC_WEBSOCKET_PROTOCOL;
+use serde::{Deserialize, Serialize};
+use crate::model::Message;
+use crate::service_config::HandlerKind;
+
+lazy_static! {
+    static ref SUB_PROTOCOLS: HashMap<String, Arc<SubProtocol>> = {
+        built_in_sub_protocols().into_iter()
+            .map(|sub_protocol| (sub_protocol.name.clone(), Arc::new(sub_protocol)))
+            .collect()
+    };
+}
+
+pub trait MessageCodec: Send + Sync {
+    // payload sent by the client
+    fn decode(&self, payload: &[u8]) -> Result<Message, &'static str>;
+    // payload sent to the client
+    fn encode(&self, message: &Message) -> Vec<u8>;
+}
+
+pub struct SubProtocol {
+    pub name: String,
+    pub handler: HandlerKind,
+    pub codec: Arc<dyn MessageCodec>
+}
+
+fn built_in_sub_protocols() -> Vec<SubProtocol> {
+    vec![
+        SubProtocol {
+            name: "v1.chat.cluster23.com".to_string(),
+            handler: HandlerKind::Chat,
+            codec: Arc::new(ChatV1Codec {})
+        },
+        SubProtocol {
+            name: "v2.chat.cluster23.com".to_string(),
+            handler: HandlerKind::Chat,
+            codec: Arc::new(ChatV2Codec {})
+        }
+    ]
+}
+
+// codec used by connections which did not negotiate a subprotocol
+pub fn default_codec() -> Arc<dyn MessageCodec> {
+    Arc::new(ChatV1Codec {})
+}
+
+//  If the server does not wish to agree to one of the suggested
+//    subprotocols, it MUST NOT send back a |Sec-WebSocket-Protocol|
+//    header field in its response.
+//
+// the first subprotocol in the client's list which the route accepts and is
+// registered is selected, the header can be sent more than once
+pub fn negotiate(header_map: &HeaderMap, sub_protocols_accepted: &[String]) -> Option<Arc<SubProtocol>> {
+    let sub_protocols_requested = header_map.get_all(SEC_WEBSOCKET_PROTOCOL).iter()
+        .filter_map(|header_value| header_value.to_str().ok())
+        .flat_map(|header_value| header_value.split(','))
+        .map(|sub_protocol| sub_protocol.trim())
+        .filter(|sub_protocol| !sub_protocol.is_empty());
+    for sub_protocol in sub_protocols_requested {
+        if !sub_protocols_accepted.iter().any(|accepted| accepted == sub_protocol) {
+            continue;
+        }
+        match SUB_PROTOCOLS.get(sub_protocol) {
+            Some(sub_protocol) => return Some(sub_protocol.clone()),
+            None => crate::error!("Subprotocol: {} accepted by route but not registered",sub_protocol)
+        }
+    }
+    None
+}
+
+//         { "sender_user_id": "user2", "message": "hi" }
+struct ChatV1Codec {}
+
+impl MessageCodec for ChatV1Codec {
+    fn decode(&self, payload: &[u8]) -> Result<Message, &'static str> {
+        serde_json::from_slice(payload).map_err(|e| {
+            crate::error!("Not able to parse v1 message: {}",e);
+            "message format not supported"
+        })
+    }
+
+    fn encode(&self, message: &Message) -> Vec<u8> {
+        serde_json::to_vec(message).unwrap()
+    }
+}
+
+//         { "to": "user2", "body": "hi" }
+//
+// to names the user the message is delivered to, v1 calls it sender_user_id
+#[derive(Deserialize,Serialize)]
+struct ChatV2Message {
+    to: String,
+    body: String
+}
+
+struct ChatV2Codec {}
+
+impl MessageCodec for ChatV2Codec {
+    fn decode(&self, payload: &[u8]) -> Result<Message, &'static str> {
+        match serde_json::from_slice::<ChatV2Message>(payload) {
+            Ok(message) => Ok(Message { sender_user_id: message.to, message: message.body }),
+            Err(e) => {
+                crate::error!("Not able to parse v2 message: {}",e);
+                Err("message format not supported")
+            }
+        }
+    }
+
+    fn encode(&self, message: &Message) -> Vec<u8> {
+        let message = ChatV2Message {
+            to: message.sender_user_id.clone(),
+            body: message.message.clone()
+        };
+        serde_json::to_vec(&message).unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use http::HeaderValue;
+    use super::*;
+
+    fn requested(header_values: &[&'static str]) -> HeaderMap {
+        let mut header_map = HeaderMap::new();
+        for header_value in header_values {
+            header_map.append(SEC_WEBSOCKET_PROTOCOL, HeaderValue::from_static(header_value));
+        }
+        header_map
+    }
+
+    fn accepted(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    fn negotiated(header_map: &HeaderMap, sub_protocols_accepted: &[String]) -> Option<String> {
+        negotiate(header_map, sub_protocols_accepted).map(|sub_protocol| sub_protocol.name.clone())
+    }
+
+    #[test]
+    fn client_preference_decides() {
+        let route = accepted(&["v1.chat.cluster23.com", "v2.chat.cluster23.com"]);
+        let header_map = requested(&[" v2.chat.cluster23.com ,v1.chat.cluster23.com"]);
+        assert_eq!(negotiated(&header_map, &route).as_deref(), Some("v2.chat.cluster23.com"));
+        // the header sent twice is one list
+        let header_map = requested(&["other", "v1.chat.cluster23.com, v2.chat.cluster23.com"]);
+        assert_eq!(negotiated(&header_map, &route).as_deref(), Some("v1.chat.cluster23.com"));
+    }
+
+    #[test]
+    fn only_subprotocols_of_the_route_are_picked() {
+        let route = accepted(&["v1.chat.cluster23.com"]);
+        let header_map = requested(&["v2.chat.cluster23.com, v1.chat.cluster23.com"]);
+        assert_eq!(negotiated(&header_map, &route).as_deref(), Some("v1.chat.cluster23.com"));
+        let header_map = requested(&["v2.chat.cluster23.com"]);
+        assert_eq!(negotiated(&header_map, &route), None);
+    }
+
+    #[test]
+    fn unknown_subprotocol_is_skipped() {
+        // accepted by the route but never registered
+        let route = accepted(&["v3.chat.cluster23.com", "v1.chat.cluster23.com"]);
+        let header_map = requested(&["v3.chat.cluster23.com, v1.chat.cluster23.com"]);
+        assert_eq!(negotiated(&header_map, &route).as_deref(), Some("v1.chat.cluster23.com"));
+        let header_map = requested(&["v3.chat.cluster23.com"]);
+        assert_eq!(negotiated(&header_map, &route), None);
+    }
+
+    #[test]
+    fn nothing_is_picked_without_the_header() {
+        let route = accepted(&["v1.chat.cluster23.com"]);
+        assert_eq!(negotiated(&HeaderMap::new(), &route), None);
+        assert_eq!(negotiated(&requested(&[" , "]), &route), None);
+    }
+
+    #[test]
+    fn v2_codec_converts_to_v1() {
+        let codec = ChatV2Codec {};
+        let message = codec.decode(br#"{"to":"user2","body":"hi"}"#).unwrap();
+        assert_eq!(message.sender_user_id, "user2");
+        assert_eq!(message.message, "hi");
+        assert_eq!(codec.encode(&message), br#"{"to":"user2","body":"hi"}"#.to_vec());
+        assert!(codec.decode(br#"{"sender_user_id":"user2","message":"hi"}"#).is_err());
+    }
+}
@@ -1,64 +1,280 @@
-use tokio::net::tcp::OwnedReadHalf;
-use tokio::io::{AsyncRead, AsyncReadExt};
-use std::cmp::min;
-use log::info;
-
-pub async fn read_specified_bytes_from_socket(read_half: &mut OwnedReadHalf, bytes_to_read: usize) -> Result<Vec<u8>, &'static str> {
-    let mut final_bytes_data:Vec<u8> = Vec::new();
-    let mut bytes_read = 0;
-    let mut bytes_left_to_read = bytes_to_read;
-    while bytes_read < bytes_to_read {
-        let mut bytes_data = vec![0;bytes_left_to_read];
-        let n: usize = match read_half.read(&mut bytes_data).await {
-            Ok(n) => n,
-            Err(e) => return Err("Not able to read data")
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use bytes::{Buf, BytesMut};
+use http::Request;
+use socket2::{SockRef, TcpKeepalive};
+use tokio::io::{AsyncRead, AsyncReadExt, Interest, ReadBuf};
+use tokio::net::TcpStream;
+use tokio::time::{Duration, Instant};
+use crate::error::http_errors::{self, ErrorKind, HTTPError};
+use crate::http_handler;
+use crate::service_config::{HandshakeConfig, TcpConfig};
+
+// every read before the websocket is open, a PROXY header, the HTTP/2 preface
+// and the request, has to be done by this deadline
+pub fn handshake_deadline(handshake_config: &HandshakeConfig) -> Instant {
+    Instant::now() + Duration::from_secs(handshake_config.timeout_secs)
+}
+
+// reads until the head of the opening handshake is complete. A client does not
+// have to wait for our response before sending frames, bytes read after the
+// head are returned with the request and belong to the frame decoder
+pub async fn read_http_request<R>(read_half: &mut R, handshake_config: &HandshakeConfig, deadline: Instant) -> http_errors::Result<(Request<()>, BytesMut)>
+    where R: AsyncRead + Unpin {
+    match tokio::time::timeout_at(deadline, read_http_request_head(read_half, handshake_config.max_header_size)).await {
+        Ok(result) => result,
+        Err(_) => {
+            crate::error!("Handshake request not received in {}s",handshake_config.timeout_secs);
+            Err(HTTPError::new(ErrorKind::RequestTimeout, "handshake request not received in time"))
+        }
+    }
+}
+
+async fn read_http_request_head<R>(read_half: &mut R, max_header_size: usize) -> http_errors::Result<(Request<()>, BytesMut)>
+    where R: AsyncRead + Unpin {
+    let mut bytes_read = BytesMut::with_capacity(1024);
+    loop {
+        crate::info!("Start reading Data from socket");
+        match read_half.read_buf(&mut bytes_read).await {
+            Ok(0) => {
+                crate::error!("Connection closed after {} bytes of the request",bytes_read.len());
+                return Err(HTTPError::new(ErrorKind::BadRequest, "connection closed during handshake"));
+            }
+            Ok(n) => crate::info!("Read {} bytes",n),
+            Err(e) => {
+                crate::error!("Not able to read handshake request: {}",e);
+                return Err(HTTPError::new(ErrorKind::BadRequest, "not able to read handshake request"));
+            }
+        }
+        let head_length = match http_handler::parse_http_request_bytes(&bytes_read)? {
+            Some((http_request, head_length)) if head_length <= max_header_size => {
+                let _ = bytes_read.split_to(head_length);
+                crate::info!("Data read Complete, {} bytes read after the request",bytes_read.len());
+                return Ok((http_request, bytes_read));
+            }
+            Some((_, head_length)) => head_length,
+            None => bytes_read.len()
         };
-        bytes_read += n;
-        bytes_left_to_read -= n;
-        final_bytes_data.append(&mut bytes_data[0..n].to_owned());
-        crate::info!("Bytes read from socket {}",n);
+        if head_length > max_header_size {
+            crate::error!("Handshake request is bigger than {} bytes",max_header_size);
+            return Err(HTTPError::new(ErrorKind::RequestHeaderFieldsTooLarge, "request headers too large"));
+        }
     }
-    Ok(final_bytes_data)
 }
 
-// this function waits for some time
-// TODO add timeout functionality
-pub async fn read_bytes_from_socket_into_buffer(read_half: &mut OwnedReadHalf, bytes_data: &mut Vec<u8>) -> Result<(), &'static str> {
-    let mut bytes_read:usize = 0;
-    let n: usize = match read_half.read_buf(bytes_data).await {
-        Ok(n) => n,
-        Err(e) => return Err("Not able to read data")
-    };
-    crate::info!("Number of bytes read {}",n);
-    Ok(())
+// keepalive finds peers which went away without a FIN while no frame is being
+// sent, e.g. with the heartbeat disabled
+pub fn set_socket_options(socket: &TcpStream, tcp_config: &TcpConfig) -> io::Result<()> {
+    socket.set_nodelay(tcp_config.nodelay)?;
+    let socket_ref = SockRef::from(socket);
+    if tcp_config.keepalive_secs > 0 {
+        socket_ref.set_tcp_keepalive(&TcpKeepalive::new().with_time(Duration::from_secs(tcp_config.keepalive_secs)))
+    } else {
+        socket_ref.set_keepalive(false)
+    }
 }
 
-pub async fn read_bytes_from_socket(read_half: &mut OwnedReadHalf) -> Result<Vec<u8>, std::io::Error> {
-    let mut bytes_data = vec![0;4096];
-    let mut ptr: usize = 0;
-    let mut data_size = 0;
+//  The client connection preface starts with a sequence of 24 octets
+//
+//     0x505249202a20485454502f322e300d0a0d0a534d0d0a0d0a
+//
+// looked at without reading it, h2 reads the preface itself. False when the
+// connection sends anything else or nothing in time.
+const HTTP2_PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+pub async fn starts_with_http2_preface(socket: &TcpStream, deadline: Instant) -> bool {
+    let mut peeked = [0u8; 24];
+    let peek = peek_until(socket, &mut peeked, |peeked| {
+        if peeked[..] != HTTP2_PREFACE[..peeked.len()] {
+            Some(false)
+        } else if peeked.len() == HTTP2_PREFACE.len() {
+            Some(true)
+        } else {
+            None
+        }
+    });
+    match tokio::time::timeout_at(deadline, peek).await {
+        Ok(Ok(starts_with_preface)) => starts_with_preface,
+        Ok(Err(e)) => {
+            crate::error!("Not able to peek at the connection: {}",e);
+            false
+        }
+        Err(_) => false
+    }
+}
+
+// looks at the first bytes of the connection until check decides what they
+// are. Check returns None while it needs more and has to decide once the
+// buffer is full. Peeking does not use up the readiness of the socket, bytes
+// which are not enough for check clear it so the next ones are waited for.
+pub async fn peek_until<T>(socket: &TcpStream, buffer: &mut [u8], mut check: impl FnMut(&[u8]) -> Option<T>) -> io::Result<T> {
+    // a duplicate of the socket to peek at it without waiting, it shares the
+    // non blocking mode with the original
+    let peek_socket: std::net::TcpStream = SockRef::from(socket).try_clone()?.into();
     loop {
-        crate::info!("Start reading Data from socket");
-        let mut buf = [0; 256];
-        let n: usize = match read_half.read(&mut buf).await {
-            Ok(n) => n,
+        socket.readable().await?;
+        let peeked = socket.try_io(Interest::READABLE, || {
+            let n = peek_socket.peek(buffer)?;
+            if n == 0 {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed"));
+            }
+            check(&buffer[..n]).ok_or_else(|| io::ErrorKind::WouldBlock.into())
+        });
+        match peeked {
+            Ok(result) => return Ok(result),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
             Err(e) => return Err(e)
-        };
-        if n <= 0 {
-            break;
         }
-        crate::info!("Read {} bytes",n);
-        data_size += n;
-        let end_ptr = min(n, bytes_data.len()-data_size);
-        for i in 0..end_ptr {
-            bytes_data[ptr] = buf[i];
-            ptr += 1;
+    }
+}
+
+// bytes read while looking for the end of the handshake are returned before
+// anything is read from the socket. FramedRead only decodes after reading from
+// its reader, with the bytes in its buffer it would wait for the peer.
+pub struct PrefixedReader<R> {
+    prefix: BytesMut,
+    inner: R
+}
+
+impl<R> PrefixedReader<R> {
+    pub fn new(prefix: BytesMut, inner: R) -> PrefixedReader<R> {
+        PrefixedReader { prefix, inner }
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for PrefixedReader<R> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        if self.prefix.is_empty() {
+            return Pin::new(&mut self.inner).poll_read(cx, buf);
+        }
+        let n = self.prefix.len().min(buf.remaining());
+        buf.put_slice(&self.prefix[..n]);
+        self.prefix.advance(n);
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::StreamExt;
+    use http::StatusCode;
+    use tokio::io::{AsyncWriteExt, DuplexStream};
+    use tokio::net::TcpListener;
+    use tokio_util::codec::{Encoder, FramedRead};
+    use crate::data_frame::{FrameValidationRules, Masking, Opcode, ReadFrom, new_dataframe, unmask_payload};
+    use crate::data_frame::codec::{FrameDecoder, FrameEncoder};
+    use super::*;
+
+    const REQUEST_HEAD: &[u8] = b"GET /chat HTTP/1.1\r\n\
+        Host: localhost\r\n\
+        Upgrade: websocket\r\n\
+        Connection: Upgrade\r\n\
+        Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+        Sec-WebSocket-Version: 13\r\n\r\n";
+
+    fn handshake_config(max_header_size: usize) -> HandshakeConfig {
+        HandshakeConfig { max_header_size, timeout_secs: 5 }
+    }
+
+    // every part is a write of its own, the reader sees them one at a time
+    fn send_in_parts(parts: Vec<Vec<u8>>) -> DuplexStream {
+        let (mut client, server) = tokio::io::duplex(16384);
+        tokio::spawn(async move {
+            for part in parts {
+                client.write_all(&part).await.unwrap();
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+            // keeps the connection open until the reader is done
+            let _ = client.read_u8().await;
+        });
+        server
+    }
+
+    #[tokio::test]
+    async fn request_split_across_reads() {
+        let mut server = send_in_parts(vec![REQUEST_HEAD[..7].to_vec(), REQUEST_HEAD[7..40].to_vec(), REQUEST_HEAD[40..].to_vec()]);
+        let handshake_config = handshake_config(8192);
+        let (request, leftover) = read_http_request(&mut server, &handshake_config, handshake_deadline(&handshake_config)).await.unwrap();
+        assert_eq!(request.uri().path(), "/chat");
+        assert_eq!(request.headers().get("sec-websocket-version").unwrap(), "13");
+        assert!(leftover.is_empty());
+    }
+
+    #[tokio::test]
+    async fn request_over_the_limit_gets_431() {
+        let handshake_config = handshake_config(256);
+        // the end of the head is never seen
+        let mut server = send_in_parts(vec![REQUEST_HEAD[..60].to_vec(), vec![b'a'; 100], vec![b'b'; 100]]);
+        match read_http_request(&mut server, &handshake_config, handshake_deadline(&handshake_config)).await {
+            Err(e) => assert_eq!(e.status_code(), StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE),
+            Ok(_) => panic!("request over max_header_size accepted")
         }
-        if n < buf.len() {
-            break;
+        // the whole head arrived but it is too big
+        let mut big_request = REQUEST_HEAD[..REQUEST_HEAD.len() - 2].to_vec();
+        big_request.extend_from_slice(format!("X-Padding: {}\r\n\r\n", "p".repeat(256)).as_bytes());
+        let mut server = send_in_parts(vec![big_request]);
+        match read_http_request(&mut server, &handshake_config, handshake_deadline(&handshake_config)).await {
+            Err(e) => assert_eq!(e.status_code(), StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE),
+            Ok(_) => panic!("request over max_header_size accepted")
         }
-        crate::info!("Copied Data");
     }
-    crate::info!("Data read Complete");
-    Ok(bytes_data[..data_size].to_owned())
+
+    #[tokio::test]
+    async fn bytes_after_the_request_go_to_the_frame_decoder() {
+        // the client sends its first frame right behind the request
+        let mut frame_bytes = BytesMut::new();
+        FrameEncoder::new_client().encode(new_dataframe(Opcode::TextFrame, b"early".to_vec()), &mut frame_bytes).unwrap();
+        let mut request_and_frame = REQUEST_HEAD.to_vec();
+        request_and_frame.extend_from_slice(&frame_bytes[..3]);
+        let mut server = send_in_parts(vec![request_and_frame, frame_bytes[3..].to_vec()]);
+
+        let handshake_config = handshake_config(8192);
+        let (_, leftover) = read_http_request(&mut server, &handshake_config, handshake_deadline(&handshake_config)).await.unwrap();
+        assert_eq!(&leftover[..], &frame_bytes[..3]);
+
+        let validation_rules = FrameValidationRules { masking: Masking::Required, rsv1_allowed: false, max_frame_size: 1024 };
+        let mut frame_reader = FramedRead::new(PrefixedReader::new(leftover, server), FrameDecoder::new(validation_rules, ReadFrom::Socket));
+        let mut data_frame = frame_reader.next().await.unwrap().unwrap();
+        unmask_payload(&mut data_frame);
+        assert_eq!(data_frame.opcode, Opcode::TextFrame);
+        assert_eq!(data_frame.payload_data, b"early");
+    }
+
+    async fn tcp_pair() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+        (client, server)
+    }
+
+    #[tokio::test]
+    async fn preface_is_peeked_as_it_arrives() {
+        let (mut client, server) = tcp_pair().await;
+        client.write_all(&HTTP2_PREFACE[..10]).await.unwrap();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            client.write_all(&HTTP2_PREFACE[10..]).await.unwrap();
+            let _ = client.read_u8().await;
+        });
+        let deadline = Instant::now() + Duration::from_secs(5);
+        assert!(starts_with_http2_preface(&server, deadline).await);
+        // nothing was read, h2 gets the whole preface
+        let mut preface = [0u8; 24];
+        assert_eq!(server.peek(&mut preface).await.unwrap(), HTTP2_PREFACE.len());
+        assert_eq!(&preface[..], HTTP2_PREFACE);
+    }
+
+    #[tokio::test]
+    async fn other_bytes_are_not_a_preface() {
+        let (mut client, server) = tcp_pair().await;
+        client.write_all(b"GET / HTTP/1.1\r\n").await.unwrap();
+        assert!(!starts_with_http2_preface(&server, Instant::now() + Duration::from_secs(5)).await);
+        // a partial preface which never completes runs into the deadline
+        let (mut client, server) = tcp_pair().await;
+        client.write_all(&HTTP2_PREFACE[..10]).await.unwrap();
+        let started = Instant::now();
+        assert!(!starts_with_http2_preface(&server, started + Duration::from_millis(100)).await);
+        assert!(started.elapsed() >= Duration::from_millis(100));
+    }
 }
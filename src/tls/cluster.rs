@@ -0,0 +1,99 @@
+// Mutual TLS between the services of a cluster. Messages for a user connected
+// to another service are relayed over these links, a peer is only trusted when
+// its certificate is signed by the cluster CA and issued for the node name.
+
+use std::convert::TryFrom;
+use std::sync::Arc;
+use tokio::net::TcpStream;
+use tokio::time::Duration;
+use tokio_rustls::{client, server, TlsAcceptor, TlsConnector};
+use tokio_rustls::rustls::{Certificate, ClientConfig, RootCertStore, ServerConfig, ServerName};
+use tokio_rustls::rustls::server::AllowAnyAuthenticatedClient;
+use crate::service_config::ClusterTlsConfig;
+
+lazy_static! {
+    pub static ref CLUSTER_TLS_ACCEPTOR: TlsAcceptor = {
+        new_cluster_acceptor(&crate::SERVICE_CONFIG.cluster_tls)
+            .unwrap_or_else(|e| panic!("Not able to set up cluster TLS: {}",e))
+    };
+    pub static ref CLUSTER_TLS_CONNECTOR: TlsConnector = {
+        new_cluster_connector(&crate::SERVICE_CONFIG.cluster_tls)
+            .unwrap_or_else(|e| panic!("Not able to set up cluster TLS: {}",e))
+    };
+}
+
+fn load_cluster_ca(cluster_tls_config: &ClusterTlsConfig) -> Result<RootCertStore, &'static str> {
+    let mut root_store = RootCertStore::empty();
+    for ca_certificate in super::load_cert_chain(&cluster_tls_config.ca_file)? {
+        root_store.add(&ca_certificate).map_err(|_| "cluster CA certificate invalid")?;
+    }
+    Ok(root_store)
+}
+
+pub fn new_cluster_acceptor(cluster_tls_config: &ClusterTlsConfig) -> Result<TlsAcceptor, &'static str> {
+    let server_config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_client_cert_verifier(AllowAnyAuthenticatedClient::new(load_cluster_ca(cluster_tls_config)?))
+        .with_single_cert(
+            super::load_cert_chain(&cluster_tls_config.cert_file)?,
+            super::load_private_key(&cluster_tls_config.key_file)?
+        )
+        .map_err(|_| "node certificate or key invalid")?;
+    Ok(TlsAcceptor::from(Arc::new(server_config)))
+}
+
+pub fn new_cluster_connector(cluster_tls_config: &ClusterTlsConfig) -> Result<TlsConnector, &'static str> {
+    let client_config = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(load_cluster_ca(cluster_tls_config)?)
+        .with_single_cert(
+            super::load_cert_chain(&cluster_tls_config.cert_file)?,
+            super::load_private_key(&cluster_tls_config.key_file)?
+        )
+        .map_err(|_| "node certificate or key invalid")?;
+    Ok(TlsConnector::from(Arc::new(client_config)))
+}
+
+// the chain is checked by rustls during the handshake, the name is checked here
+pub async fn accept_cluster_peer(socket: TcpStream, cluster_tls_config: &ClusterTlsConfig, handshake_timeout: Duration) -> Result<server::TlsStream<TcpStream>, &'static str> {
+    let tls_stream = match tokio::time::timeout(handshake_timeout, CLUSTER_TLS_ACCEPTOR.accept(socket)).await {
+        Ok(Ok(tls_stream)) => tls_stream,
+        Ok(Err(e)) => {
+            crate::error!("Cluster TLS handshake failed: {}",e);
+            return Err("cluster TLS handshake failed");
+        }
+        Err(_) => return Err("cluster TLS handshake not done in time")
+    };
+    let peer_certificates = tls_stream.get_ref().1.peer_certificates().unwrap_or(&[]);
+    match peer_certificates.first() {
+        Some(peer_certificate) if is_issued_for(peer_certificate, &cluster_tls_config.node_name) => Ok(tls_stream),
+        Some(_) => Err("peer certificate is not issued for the cluster node name"),
+        None => Err("peer sent no certificate")
+    }
+}
+
+fn is_issued_for(certificate: &Certificate, node_name: &str) -> bool {
+    let end_entity_certificate = match webpki::EndEntityCert::try_from(certificate.0.as_ref()) {
+        Ok(end_entity_certificate) => end_entity_certificate,
+        Err(_) => return false
+    };
+    match webpki::DnsNameRef::try_from_ascii_str(node_name) {
+        Ok(dns_name) => end_entity_certificate.verify_is_valid_for_dns_name(dns_name).is_ok(),
+        Err(_) => false
+    }
+}
+
+// the other service is reached by the address it registered in Redis, its
+// certificate is checked against the node name
+pub async fn connect_cluster_peer(socket: TcpStream, cluster_tls_config: &ClusterTlsConfig, handshake_timeout: Duration) -> Result<client::TlsStream<TcpStream>, &'static str> {
+    let server_name = ServerName::try_from(cluster_tls_config.node_name.as_str())
+        .map_err(|_| "cluster node name is not a valid DNS name")?;
+    match tokio::time::timeout(handshake_timeout, CLUSTER_TLS_CONNECTOR.connect(server_name, socket)).await {
+        Ok(Ok(tls_stream)) => Ok(tls_stream),
+        Ok(Err(e)) => {
+            crate::error!("Cluster TLS handshake failed: {}",e);
+            Err("cluster TLS handshake failed")
+        }
+        Err(_) => Err("cluster TLS handshake not done in time")
+    }
+}
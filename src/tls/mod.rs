@@ -0,0 +1,157 @@
+// TLS termination for wss:// connections. The certificate is picked by the
+// server name the client sends (SNI), certificate and key files are read again
+// when they change on disk, connections already open keep the old ones.
+
+use std::fs;
+use std::io::BufReader;
+use std::sync::{Arc, RwLock};
+use std::time::SystemTime;
+use tokio_rustls::rustls::{Certificate, PrivateKey, ServerConfig};
+use tokio_rustls::rustls::server::{ClientHello, ResolvesServerCert};
+use tokio_rustls::rustls::sign::{self, CertifiedKey};
+use tokio::time::Duration;
+use tokio_rustls::TlsAcceptor;
+use crate::service_config::{CertificateConfig, TlsConfig};
+
+pub mod cluster;
+
+lazy_static! {
+    pub static ref CERTIFICATE_RESOLVER: Arc<CertificateResolver> = {
+        match load_certificates(&crate::SERVICE_CONFIG.tls.certificates) {
+            Ok(certificates) => Arc::new(CertificateResolver { certificates: RwLock::new(certificates) }),
+            Err(e) => panic!("Not able to load TLS certificates: {}",e)
+        }
+    };
+    pub static ref TLS_ACCEPTOR: TlsAcceptor = {
+        new_tls_acceptor(&crate::SERVICE_CONFIG.tls, crate::SERVICE_CONFIG.http2.enabled)
+    };
+}
+
+pub struct LoadedCertificate {
+    server_names: Vec<String>,
+    certified_key: Arc<CertifiedKey>
+}
+
+pub struct CertificateResolver {
+    certificates: RwLock<Vec<LoadedCertificate>>
+}
+
+impl ResolvesServerCert for CertificateResolver {
+    fn resolve(&self, client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        let certificates = self.certificates.read().unwrap();
+        let server_name = client_hello.server_name();
+        crate::info!("TLS handshake, server name: {:?}",server_name);
+        let matching_certificate = server_name.and_then(|server_name| {
+            certificates.iter().find(|certificate| {
+                certificate.server_names.iter().any(|pattern| server_name_matches(pattern, server_name))
+            })
+        });
+        matching_certificate.or_else(|| certificates.first())
+            .map(|certificate| certificate.certified_key.clone())
+    }
+}
+
+// "*.cluster23.com" covers one level of subdomains and not cluster23.com itself
+fn server_name_matches(pattern: &str, server_name: &str) -> bool {
+    match pattern.strip_prefix("*.") {
+        Some(domain) => server_name.split_once('.')
+            .is_some_and(|(_, parent)| parent.eq_ignore_ascii_case(domain)),
+        None => pattern.eq_ignore_ascii_case(server_name)
+    }
+}
+
+// h2 is only offered when HTTP/2 connections are served
+pub fn new_tls_acceptor(tls_config: &TlsConfig, http2_enabled: bool) -> TlsAcceptor {
+    let mut server_config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_cert_resolver(CERTIFICATE_RESOLVER.clone());
+    server_config.alpn_protocols = tls_config.alpn_protocols.iter()
+        .filter(|protocol| http2_enabled || protocol.as_str() != "h2")
+        .map(|protocol| protocol.as_bytes().to_vec())
+        .collect();
+    TlsAcceptor::from(Arc::new(server_config))
+}
+
+fn load_certificates(certificate_configs: &[CertificateConfig]) -> Result<Vec<LoadedCertificate>, String> {
+    if certificate_configs.is_empty() {
+        return Err("no certificate configured".to_string());
+    }
+    certificate_configs.iter().map(|certificate_config| {
+        let certified_key = load_certified_key(certificate_config)
+            .map_err(|e| format!("{}: {}",certificate_config.cert_file,e))?;
+        crate::info!("TLS certificate loaded for: {:?}",certificate_config.server_names);
+        Ok(LoadedCertificate {
+            server_names: certificate_config.server_names.clone(),
+            certified_key: Arc::new(certified_key)
+        })
+    }).collect()
+}
+
+fn load_certified_key(certificate_config: &CertificateConfig) -> Result<CertifiedKey, &'static str> {
+    let cert_chain = load_cert_chain(&certificate_config.cert_file)?;
+    let private_key = load_private_key(&certificate_config.key_file)?;
+    let signing_key = sign::any_supported_type(&private_key).map_err(|_| "private key type not supported")?;
+    Ok(CertifiedKey::new(cert_chain, signing_key))
+}
+
+// every certificate in a PEM file, leaf first
+pub fn load_cert_chain(cert_file: &str) -> Result<Vec<Certificate>, &'static str> {
+    let cert_file = fs::File::open(cert_file).map_err(|_| "not able to read certificate file")?;
+    let cert_chain: Vec<Certificate> = rustls_pemfile::certs(&mut BufReader::new(cert_file))
+        .map_err(|_| "certificate file is not PEM")?
+        .into_iter()
+        .map(Certificate)
+        .collect();
+    if cert_chain.is_empty() {
+        return Err("no certificate in certificate file");
+    }
+    Ok(cert_chain)
+}
+
+// first RSA, PKCS#8 or EC key in a PEM file
+pub fn load_private_key(key_file: &str) -> Result<PrivateKey, &'static str> {
+    let key_file = fs::File::open(key_file).map_err(|_| "not able to read key file")?;
+    rustls_pemfile::read_all(&mut BufReader::new(key_file))
+        .map_err(|_| "key file is not PEM")?
+        .into_iter()
+        .find_map(|item| match item {
+            rustls_pemfile::Item::RSAKey(key)
+            | rustls_pemfile::Item::PKCS8Key(key)
+            | rustls_pemfile::Item::ECKey(key) => Some(PrivateKey(key)),
+            _ => None
+        })
+        .ok_or("no private key in key file")
+}
+
+// latest modification time of the certificate and key files
+fn last_modified(certificate_configs: &[CertificateConfig]) -> Option<SystemTime> {
+    certificate_configs.iter()
+        .flat_map(|certificate_config| [&certificate_config.cert_file, &certificate_config.key_file])
+        .filter_map(|file| fs::metadata(file).and_then(|metadata| metadata.modified()).ok())
+        .max()
+}
+
+// a certificate renewed on disk is picked up by the next handshake. When the
+// new files can not be loaded, e.g. key written but certificate not yet, the
+// old certificates are kept and loading is tried again after the interval.
+pub async fn watch_certificate_files(tls_config: &TlsConfig) {
+    let reload_interval = Duration::from_secs(tls_config.reload_interval_secs);
+    let mut loaded_modified_time = last_modified(&tls_config.certificates);
+    loop {
+        tokio::time::sleep(reload_interval).await;
+        let modified_time = last_modified(&tls_config.certificates);
+        if modified_time == loaded_modified_time {
+            continue;
+        }
+        crate::info!("TLS certificate files changed, reloading");
+        match load_certificates(&tls_config.certificates) {
+            Ok(certificates) => {
+                *CERTIFICATE_RESOLVER.certificates.write().unwrap() = certificates;
+                loaded_modified_time = modified_time;
+                crate::info!("TLS certificates reloaded");
+            }
+            Err(e) => crate::error!("Not able to reload TLS certificates, keeping the old ones: {}",e)
+        }
+    }
+}
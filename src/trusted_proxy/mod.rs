@@ -0,0 +1,245 @@
+// Finds the address of the client behind our load balancers. The peer of a
+// connection coming from a trusted proxy is replaced by the address the proxy
+// passes on, first from a PROXY protocol header on accept, then from the
+// Forwarded or X-Forwarded-For header of the handshake request. The address
+// found is the one the connection is logged, limited and banned by.
+
+use std::net::IpAddr;
+use http::HeaderMap;
+use http::header::FORWARDED;
+
+pub mod proxy_protocol;
+
+lazy_static! {
+    pub static ref TRUSTED_CIDRS: Vec<Cidr> = {
+        crate::SERVICE_CONFIG.trusted_proxies.cidrs.iter()
+            .map(|cidr| Cidr::parse(cidr).unwrap_or_else(|e| panic!("Trusted proxy CIDR \"{}\": {}",cidr,e)))
+            .collect()
+    };
+}
+
+static X_FORWARDED_FOR: &str = "x-forwarded-for";
+
+//         10.0.0.0/8, fd00::/8, 192.168.1.10
+//
+// an address without a prefix length is a network of its own
+pub struct Cidr {
+    network: IpAddr,
+    prefix_length: u8
+}
+
+impl Cidr {
+    pub fn parse(cidr: &str) -> Result<Cidr, &'static str> {
+        let (address, prefix_length) = match cidr.trim().split_once('/') {
+            Some((address, prefix_length)) => (address, Some(prefix_length)),
+            None => (cidr.trim(), None)
+        };
+        let network: IpAddr = address.parse().map_err(|_| "not an IP address")?;
+        let max_prefix_length = if network.is_ipv4() { 32 } else { 128 };
+        let prefix_length = match prefix_length {
+            Some(prefix_length) => prefix_length.parse::<u8>().map_err(|_| "prefix length is not a number")?,
+            None => max_prefix_length
+        };
+        if prefix_length > max_prefix_length {
+            return Err("prefix length too long for the address");
+        }
+        Ok(Cidr { network, prefix_length })
+    }
+
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        match (self.network, ip) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                prefix_matches(&network.octets(), &ip.octets(), self.prefix_length)
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                prefix_matches(&network.octets(), &ip.octets(), self.prefix_length)
+            }
+            _ => false
+        }
+    }
+}
+
+fn prefix_matches(network: &[u8], ip: &[u8], prefix_length: u8) -> bool {
+    let full_bytes = (prefix_length / 8) as usize;
+    if network[..full_bytes] != ip[..full_bytes] {
+        return false;
+    }
+    let remaining_bits = prefix_length % 8;
+    if remaining_bits == 0 {
+        return true;
+    }
+    let mask = 0xffu8 << (8 - remaining_bits);
+    network[full_bytes] & mask == ip[full_bytes] & mask
+}
+
+pub fn is_trusted(ip: IpAddr) -> bool {
+    is_in(&TRUSTED_CIDRS, ip)
+}
+
+// IPv4 clients of a dual stack socket show up as ::ffff:a.b.c.d
+fn is_in(cidrs: &[Cidr], ip: IpAddr) -> bool {
+    let ip = ip.to_canonical();
+    cidrs.iter().any(|cidr| cidr.contains(&ip))
+}
+
+//  Forwarded: for=192.0.2.60;proto=http;by=203.0.113.43, for="[2001:db8:cafe::17]:4711"
+//         X-Forwarded-For: 203.0.113.195, 70.41.3.18, 150.172.238.178
+//
+// every proxy appends the address it got the request from, the list is walked
+// from the right while the address seen so far is a trusted proxy. A value
+// which is not an address (unknown, an obfuscated identifier) stops the walk.
+// Forwarded is used when sent, X-Forwarded-For otherwise.
+pub fn client_ip(header_map: &HeaderMap, peer_ip: IpAddr, peer_trusted: bool) -> IpAddr {
+    client_ip_behind(&TRUSTED_CIDRS, header_map, peer_ip, peer_trusted)
+}
+
+fn client_ip_behind(trusted_cidrs: &[Cidr], header_map: &HeaderMap, peer_ip: IpAddr, peer_trusted: bool) -> IpAddr {
+    let mut client_ip = peer_ip.to_canonical();
+    if !peer_trusted {
+        return client_ip;
+    }
+    let forwarded_for = if header_map.contains_key(FORWARDED) {
+        forwarded_for_addresses(header_map)
+    } else {
+        x_forwarded_for_addresses(header_map)
+    };
+    for forwarded_ip in forwarded_for.iter().rev() {
+        match forwarded_ip {
+            Some(forwarded_ip) => client_ip = forwarded_ip.to_canonical(),
+            None => break
+        }
+        if !is_in(trusted_cidrs, client_ip) {
+            break;
+        }
+    }
+    client_ip
+}
+
+fn header_list_items<'a>(header_map: &'a HeaderMap, header_name: &str) -> impl Iterator<Item = &'a str> {
+    header_map.get_all(header_name).iter()
+        .filter_map(|header_value| header_value.to_str().ok())
+        .flat_map(|header_value| header_value.split(','))
+        .map(|item| item.trim())
+}
+
+fn forwarded_for_addresses(header_map: &HeaderMap) -> Vec<Option<IpAddr>> {
+    header_list_items(header_map, FORWARDED.as_str())
+        .map(|forwarded_element| {
+            forwarded_element.split(';')
+                .filter_map(|pair| pair.trim().split_once('='))
+                .find(|(name, _)| name.eq_ignore_ascii_case("for"))
+                .and_then(|(_, node)| parse_node(node.trim_matches('"')))
+        })
+        .collect()
+}
+
+fn x_forwarded_for_addresses(header_map: &HeaderMap) -> Vec<Option<IpAddr>> {
+    header_list_items(header_map, X_FORWARDED_FOR)
+        .filter(|node| !node.is_empty())
+        .map(parse_node)
+        .collect()
+}
+
+//  node = nodename [ ":" node-port ]
+//  nodename = IPv4address / "[" IPv6address "]" / "unknown" / obfnode
+//
+// X-Forwarded-For carries IPv6 addresses without brackets
+fn parse_node(node: &str) -> Option<IpAddr> {
+    if let Ok(ip) = node.parse::<IpAddr>() {
+        return Some(ip);
+    }
+    if let Some(bracketed) = node.strip_prefix('[') {
+        return bracketed.split_once(']').and_then(|(ip, _)| ip.parse().ok());
+    }
+    node.rsplit_once(':').and_then(|(ip, _)| ip.parse::<std::net::Ipv4Addr>().ok()).map(IpAddr::V4)
+}
+
+#[cfg(test)]
+mod tests {
+    use http::HeaderValue;
+    use super::*;
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    fn cidrs(cidrs: &[&str]) -> Vec<Cidr> {
+        cidrs.iter().map(|cidr| Cidr::parse(cidr).unwrap()).collect()
+    }
+
+    fn headers(headers: &[(&'static str, &'static str)]) -> HeaderMap {
+        let mut header_map = HeaderMap::new();
+        for (header_name, header_value) in headers {
+            header_map.append(*header_name, HeaderValue::from_static(header_value));
+        }
+        header_map
+    }
+
+    #[test]
+    fn cidr_prefix_edges() {
+        let everything = Cidr::parse("0.0.0.0/0").unwrap();
+        assert!(everything.contains(&ip("203.0.113.7")));
+        assert!(!everything.contains(&ip("::1")));
+        let host = Cidr::parse("192.168.1.10/32").unwrap();
+        assert!(host.contains(&ip("192.168.1.10")));
+        assert!(!host.contains(&ip("192.168.1.11")));
+        // without a prefix length the address is a network of its own
+        assert!(!Cidr::parse("192.168.1.10").unwrap().contains(&ip("192.168.1.11")));
+        let host = Cidr::parse("2001:db8::1/128").unwrap();
+        assert!(host.contains(&ip("2001:db8::1")));
+        assert!(!host.contains(&ip("2001:db8::2")));
+        let network = Cidr::parse("10.0.0.0/12").unwrap();
+        assert!(network.contains(&ip("10.15.255.255")));
+        assert!(!network.contains(&ip("10.16.0.0")));
+    }
+
+    #[test]
+    fn bad_cidrs_are_refused() {
+        assert!(Cidr::parse("10.0.0.0/33").is_err());
+        assert!(Cidr::parse("::/129").is_err());
+        assert!(Cidr::parse("10.0.0.0/").is_err());
+        assert!(Cidr::parse("10.0.0.0/-1").is_err());
+        assert!(Cidr::parse("10.0.0/8").is_err());
+        assert!(Cidr::parse("proxy.local/8").is_err());
+    }
+
+    #[test]
+    fn ipv4_mapped_address_matches_ipv4_network() {
+        let trusted_cidrs = cidrs(&["10.0.0.0/8"]);
+        assert!(is_in(&trusted_cidrs, ip("::ffff:10.1.2.3")));
+        assert!(!is_in(&trusted_cidrs, ip("::ffff:11.1.2.3")));
+    }
+
+    #[test]
+    fn x_forwarded_for_is_walked_while_hops_are_trusted() {
+        let trusted_cidrs = cidrs(&["10.0.0.0/8"]);
+        let header_map = headers(&[("x-forwarded-for", "192.0.2.1, 198.51.100.1, 10.1.1.1")]);
+        // 10.1.1.1 is trusted, 198.51.100.1 is not, what it claims is not believed
+        assert_eq!(client_ip_behind(&trusted_cidrs, &header_map, ip("10.0.0.5"), true), ip("198.51.100.1"));
+        let header_map = headers(&[("x-forwarded-for", "192.0.2.1"), ("x-forwarded-for", "10.2.2.2, 10.1.1.1")]);
+        assert_eq!(client_ip_behind(&trusted_cidrs, &header_map, ip("10.0.0.5"), true), ip("192.0.2.1"));
+    }
+
+    #[test]
+    fn headers_of_an_untrusted_peer_are_ignored() {
+        let trusted_cidrs = cidrs(&["10.0.0.0/8"]);
+        let header_map = headers(&[("x-forwarded-for", "192.0.2.1")]);
+        assert_eq!(client_ip_behind(&trusted_cidrs, &header_map, ip("203.0.113.9"), false), ip("203.0.113.9"));
+        // no header, the peer is the client
+        assert_eq!(client_ip_behind(&trusted_cidrs, &HeaderMap::new(), ip("10.0.0.5"), true), ip("10.0.0.5"));
+    }
+
+    #[test]
+    fn forwarded_is_preferred_and_unknown_stops_the_walk() {
+        let trusted_cidrs = cidrs(&["10.0.0.0/8"]);
+        let header_map = headers(&[
+            ("forwarded", r#"for=192.0.2.60;proto=http, for="[2001:db8:cafe::17]:4711", for=10.2.2.2"#),
+            ("x-forwarded-for", "1.1.1.1")
+        ]);
+        assert_eq!(client_ip_behind(&trusted_cidrs, &header_map, ip("10.0.0.5"), true), ip("2001:db8:cafe::17"));
+        let header_map = headers(&[("forwarded", "for=192.0.2.60, for=unknown")]);
+        assert_eq!(client_ip_behind(&trusted_cidrs, &header_map, ip("10.0.0.5"), true), ip("10.0.0.5"));
+        let header_map = headers(&[("x-forwarded-for", "192.0.2.1, 10.3.3.3:8080")]);
+        assert_eq!(client_ip_behind(&trusted_cidrs, &header_map, ip("10.0.0.5"), true), ip("192.0.2.1"));
+    }
+}
@@ -0,0 +1,234 @@
+// PROXY protocol header sent by a load balancer before the bytes of the client,
+// carrying the address of the client it accepted the connection from. Only the
+// header is read from the socket, everything after it belongs to the client.
+
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use tokio::io::AsyncReadExt;
+use tokio::net::TcpStream;
+use tokio::time::Instant;
+use crate::tcp_handler;
+
+//  a 108-byte buffer is always enough to store all the line and a trailing zero
+const V1_MAX_LENGTH: usize = 107;
+static V1_SIGNATURE: &[u8] = b"PROXY ";
+//  \x0D \x0A \x0D \x0A \x00 \x0D \x0A \x51 \x55 \x49 \x54 \x0A
+static V2_SIGNATURE: &[u8] = b"\r\n\r\n\0\r\nQUIT\n";
+static V2_FIXED_LENGTH: usize = 16;
+
+// the address is None when the proxy does not pass one on, for its own health
+// checks or a protocol it can not describe. The peer address is kept then.
+pub async fn read_proxy_header(socket: &mut TcpStream, deadline: Instant) -> Result<Option<SocketAddr>, &'static str> {
+    match tokio::time::timeout_at(deadline, read_header(socket)).await {
+        Ok(result) => result,
+        Err(_) => Err("PROXY protocol header not received in time")
+    }
+}
+
+// length of the header found at the start of the connection
+enum HeaderLength {
+    V1(usize),
+    V2(usize)
+}
+
+async fn read_header(socket: &mut TcpStream) -> Result<Option<SocketAddr>, &'static str> {
+    let mut peeked = [0u8; V1_MAX_LENGTH];
+    let header_length = match tcp_handler::peek_until(socket, &mut peeked, find_header).await {
+        Ok(header_length) => header_length?,
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Err("connection closed before PROXY protocol header"),
+        Err(_) => return Err("not able to read PROXY protocol header")
+    };
+    match header_length {
+        HeaderLength::V1(length) => {
+            let mut header = vec![0u8; length];
+            socket.read_exact(&mut header).await.map_err(|_| "not able to read PROXY protocol header")?;
+            // without the trailing \r\n
+            parse_v1(&header[..length - 2])
+        }
+        HeaderLength::V2(length) => {
+            let mut header = vec![0u8; length];
+            socket.read_exact(&mut header).await.map_err(|_| "not able to read PROXY protocol header")?;
+            parse_v2(&header)
+        }
+    }
+}
+
+// None while the peeked bytes could still be the start of a header
+fn find_header(peeked: &[u8]) -> Option<Result<HeaderLength, &'static str>> {
+    let n = peeked.len();
+    if peeked[..min_length(n, V2_SIGNATURE)] == V2_SIGNATURE[..min_length(n, V2_SIGNATURE)] {
+        if n < V2_FIXED_LENGTH {
+            return None;
+        }
+        let address_length = u16::from_be_bytes([peeked[14], peeked[15]]) as usize;
+        Some(Ok(HeaderLength::V2(V2_FIXED_LENGTH + address_length)))
+    } else if peeked[..min_length(n, V1_SIGNATURE)] == V1_SIGNATURE[..min_length(n, V1_SIGNATURE)] {
+        if let Some(line_end) = peeked.windows(2).position(|window| window == b"\r\n") {
+            return Some(Ok(HeaderLength::V1(line_end + 2)));
+        }
+        if n >= V1_MAX_LENGTH {
+            return Some(Err("PROXY protocol v1 header too long"));
+        }
+        None
+    } else {
+        Some(Err("PROXY protocol header missing"))
+    }
+}
+
+fn min_length(n: usize, signature: &[u8]) -> usize {
+    n.min(signature.len())
+}
+
+//  PROXY TCP4 255.255.255.255 255.255.255.255 65535 65535\r\n
+//  PROXY UNKNOWN\r\n
+fn parse_v1(line: &[u8]) -> Result<Option<SocketAddr>, &'static str> {
+    let line = std::str::from_utf8(line).map_err(|_| "PROXY protocol v1 header is not ASCII")?;
+    let fields: Vec<&str> = line.split(' ').collect();
+    match fields.get(1) {
+        Some(&"TCP4") | Some(&"TCP6") if fields.len() == 6 => {
+            let source_ip: IpAddr = fields[2].parse().map_err(|_| "PROXY protocol v1 source address invalid")?;
+            let source_port: u16 = fields[4].parse().map_err(|_| "PROXY protocol v1 source port invalid")?;
+            Ok(Some(SocketAddr::new(source_ip, source_port)))
+        }
+        Some(&"UNKNOWN") => Ok(None),
+        _ => Err("PROXY protocol v1 header malformed")
+    }
+}
+
+//  The 13th byte is the protocol version and command.
+//  The 14th byte contains the transport protocol and address family.
+//  The 15th and 16th bytes is the address length in bytes in network
+//    endian order.
+fn parse_v2(header: &[u8]) -> Result<Option<SocketAddr>, &'static str> {
+    let version = header[12] >> 4;
+    let command = header[12] & 0x0f;
+    if version != 2 {
+        return Err("PROXY protocol version not supported");
+    }
+    let addresses = &header[V2_FIXED_LENGTH..];
+    match command {
+        //  LOCAL: the connection was established on purpose by the proxy
+        //    without being relayed
+        0x0 => Ok(None),
+        0x1 => match header[13] >> 4 {
+            // AF_INET, 4 + 4 bytes of addresses then 2 + 2 of ports
+            0x1 if addresses.len() >= 12 => {
+                let source_ip = Ipv4Addr::new(addresses[0], addresses[1], addresses[2], addresses[3]);
+                let source_port = u16::from_be_bytes([addresses[8], addresses[9]]);
+                Ok(Some(SocketAddr::new(IpAddr::V4(source_ip), source_port)))
+            }
+            // AF_INET6, 16 + 16 bytes of addresses then 2 + 2 of ports
+            0x2 if addresses.len() >= 36 => {
+                let mut source_ip = [0u8; 16];
+                source_ip.copy_from_slice(&addresses[..16]);
+                let source_port = u16::from_be_bytes([addresses[32], addresses[33]]);
+                Ok(Some(SocketAddr::new(IpAddr::V6(Ipv6Addr::from(source_ip)), source_port)))
+            }
+            0x1 | 0x2 => Err("PROXY protocol v2 address block too short"),
+            // AF_UNSPEC or AF_UNIX, nothing to use as client address
+            _ => Ok(None)
+        },
+        _ => Err("PROXY protocol v2 command not supported")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn v2_header(command: u8, family: u8, addresses: &[u8]) -> Vec<u8> {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.push(0x20 | command);
+        header.push(family);
+        header.extend_from_slice(&(addresses.len() as u16).to_be_bytes());
+        header.extend_from_slice(addresses);
+        header
+    }
+
+    fn v2_length(peeked: &[u8]) -> usize {
+        match find_header(peeked) {
+            Some(Ok(HeaderLength::V2(length))) => length,
+            _ => panic!("v2 header not found")
+        }
+    }
+
+    #[test]
+    fn v1_addresses() {
+        assert_eq!(parse_v1(b"PROXY TCP4 203.0.113.7 10.0.0.1 5555 443"), Ok(Some("203.0.113.7:5555".parse().unwrap())));
+        assert_eq!(parse_v1(b"PROXY TCP6 2001:db8::5 2001:db8::1 5555 443"), Ok(Some("[2001:db8::5]:5555".parse().unwrap())));
+        assert_eq!(parse_v1(b"PROXY UNKNOWN"), Ok(None));
+        assert_eq!(parse_v1(b"PROXY UNKNOWN ffff::1 ffff::2 1 2"), Ok(None));
+    }
+
+    #[test]
+    fn malformed_v1_is_refused() {
+        assert!(parse_v1(b"PROXY TCP4 203.0.113.7 10.0.0.1 5555").is_err());
+        assert!(parse_v1(b"PROXY TCP4 203.0.113.300 10.0.0.1 5555 443").is_err());
+        assert!(parse_v1(b"PROXY TCP4 203.0.113.7 10.0.0.1 70000 443").is_err());
+        assert!(parse_v1(b"PROXY UDP4 203.0.113.7 10.0.0.1 5555 443").is_err());
+        assert!(parse_v1(b"PROXY TCP4 \xff 10.0.0.1 5555 443").is_err());
+    }
+
+    #[test]
+    fn v1_header_needs_its_line_end() {
+        assert!(find_header(b"PRO").is_none());
+        assert!(find_header(b"PROXY TCP4 203.0.113.7").is_none());
+        assert!(matches!(find_header(b"PROXY UNKNOWN\r\nGET / HTTP/1.1"), Some(Ok(HeaderLength::V1(15)))));
+        let oversized = [b'P', b'R', b'O', b'X', b'Y', b' '].iter().copied()
+            .chain(std::iter::repeat(b'1'))
+            .take(V1_MAX_LENGTH)
+            .collect::<Vec<u8>>();
+        assert!(matches!(find_header(&oversized), Some(Err(_))));
+    }
+
+    #[test]
+    fn anything_else_is_not_a_header() {
+        assert!(matches!(find_header(b"GET / HTTP/1.1\r\n"), Some(Err(_))));
+        assert!(matches!(find_header(b"\r\n\r\nX"), Some(Err(_))));
+    }
+
+    #[test]
+    fn v2_addresses() {
+        let mut addresses = vec![198, 51, 100, 9, 10, 0, 0, 1];
+        addresses.extend_from_slice(&1234u16.to_be_bytes());
+        addresses.extend_from_slice(&443u16.to_be_bytes());
+        let header = v2_header(0x1, 0x11, &addresses);
+        assert_eq!(v2_length(&header), header.len());
+        assert_eq!(parse_v2(&header), Ok(Some("198.51.100.9:1234".parse().unwrap())));
+
+        let mut addresses = "2001:db8::5".parse::<Ipv6Addr>().unwrap().octets().to_vec();
+        addresses.extend_from_slice(&[0u8; 16]);
+        addresses.extend_from_slice(&1u16.to_be_bytes());
+        addresses.extend_from_slice(&2u16.to_be_bytes());
+        // TLVs after the addresses are part of the header
+        addresses.extend_from_slice(&[0x04, 0x00, 0x02, b'a', b'b']);
+        let header = v2_header(0x1, 0x21, &addresses);
+        assert_eq!(v2_length(&header), header.len());
+        assert_eq!(parse_v2(&header), Ok(Some("[2001:db8::5]:1".parse().unwrap())));
+    }
+
+    #[test]
+    fn v2_without_a_client_address() {
+        // LOCAL, a health check of the proxy itself
+        assert_eq!(parse_v2(&v2_header(0x0, 0x00, &[])), Ok(None));
+        // AF_UNIX, 108 + 108 bytes of paths
+        assert_eq!(parse_v2(&v2_header(0x1, 0x31, &[0u8; 216])), Ok(None));
+        // AF_UNSPEC
+        assert_eq!(parse_v2(&v2_header(0x1, 0x00, &[])), Ok(None));
+    }
+
+    #[test]
+    fn truncated_or_unknown_v2_is_refused() {
+        // the fixed part is not complete yet
+        assert!(find_header(&V2_SIGNATURE[..8]).is_none());
+        assert!(find_header(&v2_header(0x1, 0x11, &[])[..15]).is_none());
+        // address block shorter than the family needs
+        assert!(parse_v2(&v2_header(0x1, 0x11, &[198, 51, 100, 9])).is_err());
+        assert!(parse_v2(&v2_header(0x1, 0x21, &[0u8; 12])).is_err());
+        // unknown command and version
+        assert!(parse_v2(&v2_header(0x2, 0x11, &[0u8; 12])).is_err());
+        let mut header = v2_header(0x1, 0x11, &[0u8; 12]);
+        header[12] = 0x11;
+        assert!(parse_v2(&header).is_err());
+    }
+}
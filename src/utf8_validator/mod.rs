@@ -0,0 +1,124 @@
+//  When an endpoint is to interpret a byte stream as UTF-8 but finds
+//    that the byte stream is not, in fact, a valid UTF-8 stream, that
+//    endpoint MUST _Fail the WebSocket Connection_.
+//
+// A text message can be split in fragments at any byte, so a code point can
+// start in one frame and end in the next one.
+
+use crate::error::data_frame_error::{DataFrameError, ErrorKind, Result};
+
+// validates a text message fragment by fragment
+#[derive(Default)]
+pub struct Utf8Validator {
+    // start of a code point at the end of the previous fragment, at most 3 bytes
+    incomplete_code_point: Vec<u8>
+}
+
+impl Utf8Validator {
+    pub fn new() -> Utf8Validator {
+        Utf8Validator {
+            incomplete_code_point: Vec::new()
+        }
+    }
+
+    pub fn validate_fragment(&mut self, mut data: &[u8], is_final_fragment: bool) -> Result<()> {
+        if !self.incomplete_code_point.is_empty() {
+            // a code point is at most 4 bytes so 3 more bytes are enough to finish it
+            let mut code_point = self.incomplete_code_point.clone();
+            let bytes_taken = data.len().min(3);
+            code_point.extend_from_slice(&data[..bytes_taken]);
+            match std::str::from_utf8(&code_point) {
+                Ok(_) => {
+                    data = &data[bytes_taken..];
+                    self.incomplete_code_point.clear();
+                }
+                Err(e) if e.valid_up_to() > 0 => {
+                    data = &data[e.valid_up_to() - self.incomplete_code_point.len()..];
+                    self.incomplete_code_point.clear();
+                }
+                Err(e) if e.error_len().is_none() => {
+                    // still not complete, whole fragment was used
+                    self.incomplete_code_point = code_point;
+                    data = &[];
+                }
+                Err(_) => return Err(invalid_utf8())
+            }
+        }
+
+        if let Err(e) = std::str::from_utf8(data) {
+            if e.error_len().is_some() {
+                return Err(invalid_utf8());
+            }
+            self.incomplete_code_point.extend_from_slice(&data[e.valid_up_to()..]);
+        }
+
+        if is_final_fragment && !self.incomplete_code_point.is_empty() {
+            return Err(invalid_utf8());
+        }
+        Ok(())
+    }
+}
+
+pub fn validate_utf8(data: &[u8]) -> Result<()> {
+    Utf8Validator::new().validate_fragment(data, true)
+}
+
+fn invalid_utf8() -> DataFrameError {
+    crate::error!("Text message is not valid utf-8");
+    DataFrameError::new(ErrorKind::InvalidPayload, "text message is not valid utf-8")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn validate_fragments(fragments: &[&[u8]]) -> Result<()> {
+        let mut validator = Utf8Validator::new();
+        for (i, fragment) in fragments.iter().enumerate() {
+            validator.validate_fragment(fragment, i == fragments.len() - 1)?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn code_point_split_across_fragments() {
+        // U+20AC, 3 bytes
+        assert!(validate_fragments(&[b"price: \xe2", b"\x82\xac"]).is_ok());
+        assert!(validate_fragments(&[b"\xe2\x82", b"\xac and more"]).is_ok());
+        // U+1F600, 4 bytes, one byte per fragment
+        assert!(validate_fragments(&[b"\xf0", b"\x9f", b"", b"\x98", b"\x80"]).is_ok());
+        // the code point is complete but the bytes after it are not valid
+        assert!(validate_fragments(&[b"\xe2", b"\x82\xac\xff"]).is_err());
+        assert!(validate_fragments(&[b"\xe2", b"\x82\xacok\xc0\xaf"]).is_err());
+    }
+
+    #[test]
+    fn overlong_encodings_are_refused() {
+        assert!(validate_utf8(b"\xc0\xaf").is_err());
+        assert!(validate_utf8(b"\xe0\x80\xaf").is_err());
+        assert!(validate_utf8(b"\xf0\x80\x80\xaf").is_err());
+        assert!(validate_fragments(&[b"\xe0", b"\x80\xaf"]).is_err());
+    }
+
+    #[test]
+    fn surrogates_are_refused() {
+        assert!(validate_utf8(b"\xed\xa0\x80").is_err());
+        assert!(validate_utf8(b"\xed\xbf\xbf").is_err());
+        assert!(validate_fragments(&[b"\xed", b"\xa0\x80"]).is_err());
+    }
+
+    #[test]
+    fn code_points_above_u10ffff_are_refused() {
+        assert!(validate_utf8(b"\xf4\x8f\xbf\xbf").is_ok());
+        assert!(validate_utf8(b"\xf4\x90\x80\x80").is_err());
+        assert!(validate_utf8(b"\xf5\x80\x80\x80").is_err());
+        assert!(validate_fragments(&[b"\xf4\x90", b"\x80\x80"]).is_err());
+    }
+
+    #[test]
+    fn truncated_code_point_at_fin_is_refused() {
+        assert!(validate_utf8(b"abc\xe2\x82").is_err());
+        assert!(validate_fragments(&[b"abc", b"\xf0\x9f\x98"]).is_err());
+        assert!(validate_fragments(&[b"\xf0\x9f", b"\x98", b""]).is_err());
+    }
+}
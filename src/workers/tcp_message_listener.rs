@@ -1,30 +1,68 @@
+use futures_util::StreamExt;
+use tokio::io::AsyncRead;
 use tokio::net::TcpListener;
-use crate::data_frame::read_next_dataframe_from_socket;
-use crate::tcp_handler::read_specified_bytes_from_socket;
+use tokio::time::Duration;
+use tokio_util::codec::FramedRead;
+use crate::{channel_handler, route_table, SERVICE_CONFIG};
+use crate::data_frame::{FrameValidationRules, Masking, ReadFrom};
+use crate::data_frame::codec::FrameDecoder;
 use crate::model::Message;
+use crate::tls::cluster;
 
 pub async fn listen_for_messages_from_other_services(addr: String) {
     let listener = TcpListener::bind(addr).await.unwrap();
 
     loop {
-        let (socket, _) = listener.accept().await.unwrap();
-        let (mut read_half, _) = socket.into_split();
-
-        let data_frame = read_next_dataframe_from_socket(&mut read_half).await;
-
-        let data = read_specified_bytes_from_socket(&mut read_half,data_frame.payload_length).await.unwrap();
+        let (socket, socket_address) = match listener.accept().await {
+            Ok(connection) => connection,
+            Err(e) => {
+                crate::error!("Not able to accept connection from other service: {}",e);
+                continue;
+            }
+        };
+        tokio::spawn(async move {
+            if !SERVICE_CONFIG.cluster_tls.enabled {
+                read_messages_from_other_service(socket).await;
+                return;
+            }
+            let handshake_timeout = Duration::from_secs(SERVICE_CONFIG.handshake.timeout_secs);
+            match cluster::accept_cluster_peer(socket, &SERVICE_CONFIG.cluster_tls, handshake_timeout).await {
+                Ok(tls_stream) => read_messages_from_other_service(tls_stream).await,
+                Err(e) => crate::error!("Connection from {} refused, not a service of the cluster: {}",socket_address,e)
+            }
+        });
+    }
+}
 
-        let message: Message = serde_json::from_str(std::str::from_utf8(&data).unwrap()).unwrap();
-        let user_id_mapping = crate::USER_ID_MAPPING.lock().await;
-        let user_sender = user_id_mapping.get(&message.sender_user_id).unwrap();
-        let mut vec_to_send: Vec<Vec<u8>> = Vec::new();
-        vec_to_send.push(data_frame.raw_bytes);
-        vec_to_send.push(data);
+async fn read_messages_from_other_service<R>(socket: R)
+    where R: AsyncRead + Unpin {
+    // frames forwarded by other services are not masked and carry a whole message
+    let validation_rules = FrameValidationRules {
+        masking: Masking::Optional,
+        rsv1_allowed: false,
+        max_frame_size: route_table::largest_message_size()
+    };
+    let mut frame_reader = FramedRead::new(socket, FrameDecoder::new(validation_rules, ReadFrom::Channel));
 
-        let tx2 = user_sender.clone();
-        for bytes in vec_to_send {
-            tx2.send(bytes).await;
+    while let Some(data_frame) = frame_reader.next().await {
+        let data_frame = match data_frame {
+            Ok(data_frame) => data_frame,
+            Err(e) => {
+                crate::error!("Not able to read message from other service: {}",e);
+                return;
+            }
+        };
+        let message: Message = match serde_json::from_slice(&data_frame.payload_data) {
+            Ok(message) => message,
+            Err(e) => {
+                crate::error!("Not able to parse message from other service: {}",e);
+                continue;
+            }
+        };
+        let user_sender = crate::USER_ID_MAPPING.lock().await.get(&message.sender_user_id).cloned();
+        match user_sender {
+            Some(tx) => channel_handler::send_dataframe_to_channel(data_frame, &tx).await,
+            None => crate::error!("User Not Connected to this Service")
         }
-
     }
 }
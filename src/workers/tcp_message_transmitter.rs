@@ -1,9 +1,36 @@
+use futures_util::SinkExt;
+use tokio::io::AsyncWrite;
 use tokio::net::TcpStream;
-use tokio::io::AsyncWriteExt;
+use tokio::time::Duration;
+use tokio_util::codec::FramedWrite;
+use crate::SERVICE_CONFIG;
+use crate::data_frame::DataFrameInfo;
+use crate::data_frame::codec::FrameEncoder;
+use crate::tls::cluster;
 
-pub async fn transmit(to_send: Vec<Vec<u8>>, ip_address: String) {
-    let mut tcp_stream =TcpStream::connect(ip_address).await.unwrap();
-    for vec in to_send {
-        tcp_stream.write(&vec).await;
+pub async fn transmit(data_frame: DataFrameInfo, ip_address: String) {
+    let tcp_stream = match TcpStream::connect(&ip_address).await {
+        Ok(tcp_stream) => tcp_stream,
+        Err(e) => {
+            crate::error!("Not able to connect to service {}: {}",ip_address,e);
+            return;
+        }
+    };
+    if !SERVICE_CONFIG.cluster_tls.enabled {
+        send_frame(tcp_stream, data_frame, &ip_address).await;
+        return;
+    }
+    let handshake_timeout = Duration::from_secs(SERVICE_CONFIG.handshake.timeout_secs);
+    match cluster::connect_cluster_peer(tcp_stream, &SERVICE_CONFIG.cluster_tls, handshake_timeout).await {
+        Ok(tls_stream) => send_frame(tls_stream, data_frame, &ip_address).await,
+        Err(e) => crate::error!("Not able to connect to service {}: {}",ip_address,e)
+    }
+}
+
+async fn send_frame<W>(write_half: W, data_frame: DataFrameInfo, ip_address: &str)
+    where W: AsyncWrite + Unpin {
+    let mut frame_writer = FramedWrite::new(write_half, FrameEncoder::new());
+    if let Err(e) = frame_writer.send(data_frame).await {
+        crate::error!("Not able to send message to service {}: {}",ip_address,e);
     }
 }